@@ -19,3 +19,28 @@ impl fmt::Display for GitError {
 }
 
 impl Error for GitError {}
+
+/// Authentication was needed, but prompting for credentials was disabled.
+#[derive(Debug)]
+pub struct AuthRequiredError {
+    url: String,
+}
+
+impl AuthRequiredError {
+    pub fn new(url: String) -> Self {
+        AuthRequiredError { url }
+    }
+}
+
+impl fmt::Display for AuthRequiredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "authentication required for {}, but terminal prompts are disabled \
+             (use a credential helper, the SSH agent or GIT_ASKPASS)",
+            self.url
+        )
+    }
+}
+
+impl Error for AuthRequiredError {}
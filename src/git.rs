@@ -1,16 +1,38 @@
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use git2::{
-    self, Branch, BranchType, Config, FetchOptions, FetchPrune, ObjectType, Oid, Remote,
-    RemoteCallbacks, Repository,
+    self, AutotagOption, Branch, BranchType, CertificateCheckStatus, Commit, Config, ConfigLevel,
+    Cred, CredentialType, ErrorClass, ErrorCode, FetchOptions, FetchPrune, ObjectType, Oid,
+    ProxyOptions, RebaseOptions, Reference, Remote, RemoteCallbacks, Repository, Signature, Sort,
+    StashApplyOptions, StatusOptions, WorktreeLockStatus,
 };
 use git2_credentials::CredentialHandler;
 
-use crate::err::GitError;
+use crate::err::{AuthRequiredError, GitError};
+use crate::http;
+use crate::journal::{Journal, JournalEntry};
+use crate::known_hosts;
+use crate::progress::ProgressLine;
+use crate::prompt::Prompt;
+use crate::token;
 
 pub struct Git {
     repo: Repository,
     config: Config,
+    /// Other worktrees by the branch checked out there.
+    worktrees: OnceCell<HashMap<String, PathBuf>>,
+    journal: Journal,
+    /// Show the progress of fetches on stderr.
+    progress: bool,
+    /// Prompt for credentials on the terminal.
+    prompt: bool,
 }
 
 macro_rules! ostr {
@@ -32,6 +54,161 @@ fn prefix_stripped<'a>(s: &'a str, prefix: &str) -> &'a str {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefKind {
+    Branch,
+    Tag,
+    Ref,
+}
+
+impl RefKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefKind::Branch => "branch",
+            RefKind::Tag => "tag",
+            RefKind::Ref => "ref",
+        }
+    }
+}
+
+/// A ref updated by fetch.
+#[derive(Clone, Debug)]
+pub struct RefUpdate {
+    /// Full name of the local ref, e.g. "refs/remotes/origin/main".
+    pub refname: String,
+    pub kind: RefKind,
+    /// Short name on the remote, e.g. "main".
+    pub src: String,
+    /// Short name of the local ref, e.g. "origin/main".
+    pub dst: String,
+    /// Zero if the ref is new.
+    pub old: Oid,
+    /// Zero if the ref is deleted.
+    pub new: Oid,
+    pub forced: bool,
+}
+
+impl RefUpdate {
+    fn new(refname: &str, old: Oid, new: Oid) -> Self {
+        RefUpdate {
+            refname: refname.to_string(),
+            kind: RefKind::Ref,
+            src: refname.to_string(),
+            dst: refname.to_string(),
+            old,
+            new,
+            forced: false,
+        }
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.old.is_zero()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.new.is_zero()
+    }
+}
+
+/// Local tag whose value differs from the remote.
+#[derive(Clone, Debug)]
+pub struct TagMismatch {
+    /// Short name, e.g. "v1.0".
+    pub name: String,
+    pub local: Oid,
+    pub remote: Oid,
+}
+
+/// Result of a fetch.
+#[derive(Debug, Default)]
+pub struct Fetched {
+    pub updates: Vec<RefUpdate>,
+    /// Lines sent by the remote over the sideband, without "remote: " prefix.
+    pub messages: Vec<String>,
+}
+
+/// Result of comparing local tags with the remote.
+#[derive(Debug, Default)]
+pub struct TagSync {
+    /// Tags deleted locally, and tags clobbered by fetch.
+    pub updates: Vec<RefUpdate>,
+    /// Tags that differ from the remote, updated with clobber.
+    pub clobbered: Vec<TagMismatch>,
+    /// Tags that differ from the remote, left untouched.
+    pub mismatches: Vec<TagMismatch>,
+}
+
+/// Namespace of deleted branches, as `<prefix><date>/<name>`, or `<prefix><date>.<n>/<name>`
+/// for the n-th backup of the name on the date.
+const TRASH_PREFIX: &str = "refs/hubsync/trash/";
+
+/// Namespace of branch tips replaced by reset, named like the trash.
+const RESET_PREFIX: &str = "refs/hubsync/reset/";
+
+/// Namespace of tags deleted by tag sync, named like the trash.
+const TAG_TRASH_PREFIX: &str = "refs/hubsync/tags/";
+
+/// Keys of `branch.<name>` config kept with a deleted branch.
+const BRANCH_CONFIG_KEYS: [&str; 3] = ["remote", "merge", "pushremote"];
+
+/// Days to keep deleted branches unless `hubsync.trashRetention` is set.
+const DEFAULT_TRASH_RETENTION: i64 = 30;
+
+/// A deleted branch kept in the trash.
+#[derive(Clone, Debug)]
+pub struct Backup {
+    /// e.g. "refs/hubsync/trash/2024-01-31/topic", or "refs/hubsync/trash/2024-01-31.2/topic"
+    /// if the branch was deleted again on the day.
+    pub refname: String,
+    /// Date of deletion in UTC, as "YYYY-MM-DD".
+    pub date: String,
+    pub name: String,
+    pub oid: Oid,
+    /// 1 for the first backup of the name on the date, 2 for the next one, and so on.
+    seq: u32,
+}
+
+impl Backup {
+    fn from_refname(refname: &str, oid: Oid) -> Option<Self> {
+        let (dir, name) = refname.strip_prefix(TRASH_PREFIX)?.split_once('/')?;
+        let (date, seq) = match dir.split_once('.') {
+            Some((date, seq)) => (date, seq.parse().ok()?),
+            None => (dir, 1),
+        };
+        Some(Backup {
+            refname: refname.to_string(),
+            date: date.to_string(),
+            name: name.to_string(),
+            oid,
+            seq,
+        })
+    }
+
+    /// Config section where the upstream config of the branch is kept, named after the ref.
+    fn config_key(&self, key: &str) -> String {
+        let section = prefix_stripped(&self.refname, "refs/hubsync/");
+        format!("hubsync.{}.{}", section, key)
+    }
+}
+
+/// How `git pull` integrates a diverged branch, by `branch.<name>.rebase` or `pull.rebase`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PullRebase {
+    Rebase,
+    Merge,
+    /// `merges` or `interactive`, as `<key>=<value>`, which hubsync does not do.
+    Unsupported(String),
+}
+
+/// Commits of a branch and its upstream that the other does not have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub ahead: usize,
+    pub behind: usize,
+    /// None if the branch and the upstream have no common history.
+    pub base: Option<Oid>,
+}
+
 pub struct Range<'a> {
     repo: &'a Repository,
     beg: Oid,
@@ -50,25 +227,166 @@ impl Range<'_> {
     pub fn is_ancestor(&self) -> Result<bool, Box<dyn Error>> {
         Ok(self.repo.graph_descendant_of(self.end, self.beg)?)
     }
+
+    /// Commits of beg not in end, commits of end not in beg, and their merge base.
+    pub fn divergence(&self) -> Result<Divergence, Box<dyn Error>> {
+        let (ahead, behind) = self.repo.graph_ahead_behind(self.beg, self.end)?;
+        Ok(Divergence {
+            ahead,
+            behind,
+            base: self.merge_base()?,
+        })
+    }
+
+    /// Check if beg is merged into end, by ancestry, squash merge or rebase merge.
+    pub fn is_merged(&self) -> Result<bool, Box<dyn Error>> {
+        if self.is_identical() || self.is_ancestor()? {
+            return Ok(true);
+        }
+        let base = match self.merge_base()? {
+            Some(base) => base,
+            None => return Ok(false),
+        };
+        let patchids = self.patchids(self.end, base)?;
+        Ok(self.is_squash_merged(base, &patchids)? || self.is_rebase_merged(base, &patchids)?)
+    }
+
+    /// Check if the net change of beg since the merge base landed on end as a single commit.
+    fn is_squash_merged(&self, base: Oid, patchids: &HashSet<Oid>) -> Result<bool, Box<dyn Error>> {
+        let base_tree = self.repo.find_commit(base)?.tree()?;
+        let beg_tree = self.repo.find_commit(self.beg)?.tree()?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(Some(&base_tree), Some(&beg_tree), None)?;
+        if diff.deltas().len() == 0 {
+            return Ok(false);
+        }
+        Ok(patchids.contains(&diff.patchid(None)?))
+    }
+
+    /// Check if every commit of beg since the merge base has an equivalent commit on end,
+    /// like `git cherry`.
+    fn is_rebase_merged(&self, base: Oid, patchids: &HashSet<Oid>) -> Result<bool, Box<dyn Error>> {
+        let mut found = false;
+        for oid in self.revwalk(self.beg, base)? {
+            let commit = self.repo.find_commit(oid?)?;
+            match commit_patchid(self.repo, &commit)? {
+                Some(patchid) if patchids.contains(&patchid) => found = true,
+                Some(_) => return Ok(false),
+                None => {}
+            }
+        }
+        Ok(found)
+    }
+
+    fn patchids(&self, push: Oid, hide: Oid) -> Result<HashSet<Oid>, Box<dyn Error>> {
+        let mut patchids = HashSet::new();
+        for oid in self.revwalk(push, hide)? {
+            let commit = self.repo.find_commit(oid?)?;
+            if let Some(patchid) = commit_patchid(self.repo, &commit)? {
+                patchids.insert(patchid);
+            }
+        }
+        Ok(patchids)
+    }
+
+    fn revwalk(&self, push: Oid, hide: Oid) -> Result<git2::Revwalk<'_>, Box<dyn Error>> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+        revwalk.push(push)?;
+        revwalk.hide(hide)?;
+        Ok(revwalk)
+    }
+
+    fn merge_base(&self) -> Result<Option<Oid>, Box<dyn Error>> {
+        match self.repo.merge_base(self.beg, self.end) {
+            Ok(oid) => Ok(Some(oid)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Patch-id of the change introduced by the commit, or None for a merge commit.
+fn commit_patchid(repo: &Repository, commit: &Commit) -> Result<Option<Oid>, Box<dyn Error>> {
+    if commit.parent_count() > 1 {
+        return Ok(None);
+    }
+    let parent_tree = if commit.parent_count() == 1 {
+        Some(commit.parent(0)?.tree()?)
+    } else {
+        None
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    Ok(Some(diff.patchid(None)?))
 }
 
 impl Git {
     pub fn new(repo: Repository, config: Config) -> Self {
-        Git { repo, config }
+        let journal = Journal::new(repo.commondir());
+        Git {
+            repo,
+            config,
+            worktrees: OnceCell::new(),
+            journal,
+            progress: false,
+            prompt: env::var("GIT_TERMINAL_PROMPT")
+                .map_or(true, |value| Config::parse_bool(value).unwrap_or(true)),
+        }
+    }
+
+    /// Show an in-place progress line on stderr while fetching, which is off by default.
+    /// It is meant to be enabled only if stderr is a terminal.
+    pub fn set_progress(&mut self, progress: bool) {
+        self.progress = progress;
+    }
+
+    /// Prompt for credentials on the terminal if needed, which is on by default unless
+    /// `GIT_TERMINAL_PROMPT` is false. Without it, a fetch that needs credentials not
+    /// provided by helpers, the SSH agent, key files or askpass fails with
+    /// `AuthRequiredError`.
+    pub fn set_prompt(&mut self, prompt: bool) {
+        self.prompt = prompt;
     }
 
     pub fn checkout(&self, branch: &Branch) -> Result<(), Box<dyn Error>> {
+        // Checked before the files are switched, as set_head would fail afterwards.
+        if let Some(path) = self.worktree_of(branch)? {
+            return Err(GitError::new(format!(
+                "'{}' is already checked out at {}",
+                ostr!(branch.name()?),
+                path.display()
+            ))
+            .into());
+        }
+        let old = self.head_target()?;
+        let new = ostr!(branch.get().name()).to_string();
         self.repo
             .checkout_tree(&branch.get().peel(ObjectType::Commit)?, None)?;
-        self.repo.set_head(ostr!(branch.get().name()))?;
-        Ok(())
+        self.repo.set_head(&new)?;
+        self.journal.record(&JournalEntry::Head { old, new })
     }
 
-    pub fn current_branch(&self) -> Result<Branch<'_>, Box<dyn Error>> {
+    /// Symbolic target of HEAD, or OID if detached.
+    fn head_target(&self) -> Result<String, Box<dyn Error>> {
+        let head = self.repo.find_reference("HEAD")?;
+        match head.symbolic_target() {
+            Some(target) => Ok(target.to_string()),
+            None => Ok(ostr!(head.target()).to_string()),
+        }
+    }
+
+    /// Current branch, or None if HEAD is detached or unborn.
+    pub fn current_branch(&self) -> Result<Option<Branch<'_>>, Box<dyn Error>> {
         if self.repo.head_detached()? {
-            Err(GitError::new("Head is detached".to_string()).into())
-        } else {
-            Ok(Branch::wrap(self.repo.head()?))
+            return Ok(None);
+        }
+        match self.repo.head() {
+            Ok(head) => Ok(Some(Branch::wrap(head))),
+            Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -76,13 +394,30 @@ impl Git {
         &self,
         remote: &Remote,
     ) -> Result<(Branch<'_>, Option<Branch<'_>>), Box<dyn Error>> {
-        let buf = remote.default_branch()?;
-        let default_ref = ostr!(buf.as_str());
-        let default_name = format!(
-            "{}/{}",
-            ostr!(remote.name()),
-            prefix_stripped(default_ref, "refs/heads/")
-        );
+        let remote_name = ostr!(remote.name());
+        let default_ref = match remote.default_branch() {
+            Ok(buf) => prefix_stripped(ostr!(buf.as_str()), "refs/heads/").to_string(),
+            Err(e) => {
+                // Not connected (fetch skipped), fall back to refs/remotes/<remote>/HEAD,
+                // then to "main" or "master".
+                if let Ok(head) = self
+                    .repo
+                    .find_reference(&format!("refs/remotes/{}/HEAD", remote_name))
+                {
+                    let target = ostr!(head.symbolic_target());
+                    prefix_stripped(target, &format!("refs/remotes/{}/", remote_name)).to_string()
+                } else if let Some(name) = ["main", "master"].into_iter().find(|name| {
+                    self.repo
+                        .find_branch(&format!("{}/{}", remote_name, name), BranchType::Remote)
+                        .is_ok()
+                }) {
+                    name.to_string()
+                } else {
+                    return Err(e.into());
+                }
+            }
+        };
+        let default_name = format!("{}/{}", remote_name, default_ref);
         for result in self.repo.branches(Some(BranchType::Local))? {
             let (branch, _) = result?;
             if let Ok(upstream) = branch.upstream()
@@ -110,89 +445,444 @@ impl Git {
         s: &str,
         from: Oid,
         to: Oid,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<RefUpdate, Box<dyn Error>> {
+        let mut update = RefUpdate::new(s, from, to);
         if to.is_zero() {
-            println!(
-                " - [deleted]               {:14} -> {}",
-                "(none)",
-                prefix_stripped(s, "refs/remotes/")
-            );
-            return Ok(());
+            if s.starts_with("refs/remotes/") {
+                update.kind = RefKind::Branch;
+            } else if s.starts_with("refs/tags/") {
+                update.kind = RefKind::Tag;
+            }
+            update.src = "(none)".to_string();
+            update.dst = prefix_stripped(s, "refs/remotes/").to_string();
+            return Ok(update);
         }
         let refer = self.repo.find_reference(s)?;
-        let (mark, from_name, to_name) = if refer.is_tag() {
+        if refer.is_tag() {
             let name = ostr!(refer.shorthand());
-            ("tag", name.to_string(), name.to_string())
+            update.kind = RefKind::Tag;
+            update.src = name.to_string();
+            update.dst = name.to_string();
         } else {
-            let mut result = ("ref", s.to_string(), s.to_string());
             for refspec in remote.refspecs() {
                 if let Ok(src) = refspec.rtransform(s) {
                     if refer.is_remote() {
-                        result = (
-                            "branch",
-                            prefix_stripped(ostr!(src.as_str()), "refs/heads/").to_string(),
-                            ostr!(refer.shorthand()).to_string(),
-                        );
+                        update.kind = RefKind::Branch;
+                        update.src =
+                            prefix_stripped(ostr!(src.as_str()), "refs/heads/").to_string();
+                        update.dst = ostr!(refer.shorthand()).to_string();
                     } else {
-                        result = ("ref", ostr!(src.as_str()).to_string(), s.to_string());
+                        update.src = ostr!(src.as_str()).to_string();
                     }
                     break;
                 }
             }
-            result
-        };
-        if from.is_zero() {
-            println!(
-                " * {:24}{:14} -> {}",
-                format!("[new {}]", mark),
-                from_name,
-                to_name
-            );
-        } else {
+        }
+        if !from.is_zero() {
             let range = Range {
                 repo: &self.repo,
                 beg: from,
                 end: to,
             };
-            if range.is_ancestor().unwrap_or(false) {
-                println!(
-                    "   {:.10}..{:.10}  {:14} -> {:14}",
-                    from, to, from_name, to_name
-                );
-            } else {
-                println!(
-                    " + {:.10}..{:.10}  {:14} -> {:14} (forced update)",
-                    from, to, from_name, to_name
-                );
-            }
+            update.forced = !range.is_ancestor().unwrap_or(false);
         }
-        Ok(())
+        Ok(update)
     }
 
-    pub fn fetch(&self, remote: &mut Remote) -> Result<(), Box<dyn Error>> {
+    /// Fetch the remote with pruning, reporting updated refs and messages of the remote.
+    ///
+    /// CA certificates of `http.<url>.sslCAInfo` and `http.<url>.sslCAPath` are added to
+    /// the certificate store shared by libgit2 in the process, and cannot be removed.
+    /// Once a remote is fetched, its CA stays trusted for later HTTPS fetches of the
+    /// process, including fetches of other remotes.
+    pub fn fetch(&self, remote: &mut Remote) -> Result<Fetched, Box<dyn Error>> {
         let fetch_refspecs = remote.fetch_refspecs()?;
         let mut refspecs = vec![];
         for refspec in fetch_refspecs.iter() {
-            refspecs.push(ostr!(refspec));
+            refspecs.push(ostr!(refspec).to_string());
         }
-        let mut remote_callbacks = RemoteCallbacks::new();
-        let config = self.repo.config()?;
-        let mut ch = CredentialHandler::new(config);
-        remote_callbacks.credentials(move |url, username_from_url, allowed_types| {
-            ch.try_next_credential(url, username_from_url, allowed_types)
-        });
+        self.fetch_refspecs(
+            remote,
+            &refspecs,
+            FetchPrune::On,
+            AutotagOption::Unspecified,
+        )
+    }
 
-        let remote_clone = remote.clone();
-        remote_callbacks.update_tips(move |s, from, to| {
-            if let Err(e) = self.update_tips(&remote_clone, s, from, to) {
-                println!("s: {}", e);
+    fn fetch_refspecs(
+        &self,
+        remote: &mut Remote,
+        refspecs: &[String],
+        prune: FetchPrune,
+        autotag: AutotagOption,
+    ) -> Result<Fetched, Box<dyn Error>> {
+        let (proxy, tls, ssh_port) = match remote.url() {
+            Some(url) => (
+                http::proxy_for(&self.config, ostr!(remote.name()), url)?,
+                http::tls_for(&self.config, url)?,
+                known_hosts::ssh_port(url),
+            ),
+            None => (None, None, 22),
+        };
+        if let Some(tls) = &tls {
+            set_ssl_cert_locations(tls)?;
+        }
+        let verify = tls.is_none_or(|tls| tls.verify);
+        let mut updates = vec![];
+        let host_key_error = RefCell::new(None);
+        let auth_required = Rc::new(Cell::new(false));
+        let progress = RefCell::new(ProgressLine::new(self.progress));
+        let result = {
+            let mut remote_callbacks = RemoteCallbacks::new();
+            let config = self.repo.config()?;
+            let prompt = Prompt::new(&config, self.prompt, Rc::clone(&auth_required));
+            let mut ch = CredentialHandler::new_with_ui(config, Box::new(prompt));
+            let mut token_tried = false;
+            // A token of the forge first, then helpers, the SSH agent, key files and prompts.
+            remote_callbacks.credentials(move |url, username_from_url, allowed_types| {
+                if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) && !token_tried {
+                    token_tried = true;
+                    let token = token::token_for(&self.config, url)
+                        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+                    if let Some(token) = token {
+                        return Cred::userpass_plaintext(&token.username, &token.token);
+                    }
+                }
+                ch.try_next_credential(url, username_from_url, allowed_types)
+            });
+
+            let remote_clone = remote.clone();
+            let updates = &mut updates;
+            remote_callbacks.update_tips(move |s, from, to| {
+                let update = self
+                    .update_tips(&remote_clone, s, from, to)
+                    .unwrap_or_else(|_| RefUpdate::new(s, from, to));
+                updates.push(update);
+                true
+            });
+            let host_key_error = &host_key_error;
+            remote_callbacks.certificate_check(move |cert, host| {
+                if let Some(hostkey) = cert.as_hostkey() {
+                    // libgit2 replaces the message of the error, so keep it aside.
+                    return match known_hosts::verify_host_key(&self.config, host, ssh_port, hostkey)
+                    {
+                        Ok(()) => Ok(CertificateCheckStatus::CertificateOk),
+                        Err(e) => {
+                            let message = e.to_string();
+                            let error = git2::Error::from_str(&message);
+                            *host_key_error.borrow_mut() = Some(message);
+                            Err(error)
+                        }
+                    };
+                }
+                if cert.as_x509().is_some() && !verify {
+                    Ok(CertificateCheckStatus::CertificateOk)
+                } else {
+                    Ok(CertificateCheckStatus::CertificatePassthrough)
+                }
+            });
+            let progress = &progress;
+            remote_callbacks.sideband_progress(move |data| {
+                progress.borrow_mut().sideband(data);
+                true
+            });
+            remote_callbacks.transfer_progress(move |stats| {
+                progress.borrow_mut().update(&stats);
+                true
+            });
+            let mut fetch_options = FetchOptions::new();
+            fetch_options.remote_callbacks(remote_callbacks);
+            fetch_options.prune(prune);
+            fetch_options.download_tags(autotag);
+            let mut proxy_options = ProxyOptions::new();
+            if let Some(proxy) = &proxy {
+                proxy_options.url(proxy);
+            }
+            fetch_options.proxy_options(proxy_options);
+            remote.fetch(refspecs, Some(&mut fetch_options), None)
+        };
+        let mut progress = progress.into_inner();
+        progress.finish();
+        if let Err(e) = result {
+            if let Some(message) = host_key_error.into_inner() {
+                return Err(GitError::new(message).into());
+            }
+            if auth_required.get() {
+                let url = remote.url().unwrap_or_default().to_string();
+                return Err(AuthRequiredError::new(url).into());
+            }
+            if e.class() == ErrorClass::Ssl && verify {
+                return Err(GitError::new(format!(
+                    "{} (set http.sslCAInfo to trust its CA, or http.sslVerify to false)",
+                    e.message()
+                ))
+                .into());
             }
-            true
-        });
-        let mut fetch_options = FetchOptions::new();
-        fetch_options.remote_callbacks(remote_callbacks);
-        fetch_options.prune(FetchPrune::On);
-        Ok(remote.fetch(&refspecs, Some(&mut fetch_options), None)?)
+            return Err(e.into());
+        }
+        Ok(Fetched {
+            updates,
+            messages: progress.into_messages(),
+        })
+    }
+
+    /// Check if the remote was fetched less than `hubsync.fetchMaxAge` seconds ago, by
+    /// the time of `FETCH_HEAD` written for its URL or of the last fetch in the reflog
+    /// of its remote-tracking branches. Always false if unset or 0.
+    pub fn fetched_recently(&self, remote: &Remote) -> Result<bool, Box<dyn Error>> {
+        let max_age = match self.config.get_i64("hubsync.fetchMaxAge") {
+            Ok(max_age) => max_age,
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if max_age <= 0 {
+            return Ok(false);
+        }
+        let Some(last) = self.last_fetch(remote)? else {
+            return Ok(false);
+        };
+        Ok(seconds_since_epoch() - last < max_age)
+    }
+
+    /// Time of the last fetch of the remote, in seconds since the Unix epoch.
+    fn last_fetch(&self, remote: &Remote) -> Result<Option<i64>, Box<dyn Error>> {
+        let mut last = None;
+        let fetch_head = self.repo.path().join("FETCH_HEAD");
+        // git writes the URL without trailing ".git" in FETCH_HEAD, libgit2 as is.
+        if let Some(url) = remote.url()
+            && let Ok(content) = fs::read_to_string(&fetch_head)
+        {
+            let short = url.trim_end_matches('/').trim_end_matches(".git");
+            if content
+                .lines()
+                .any(|line| line.ends_with(url) || line.ends_with(short))
+            {
+                let mtime = fs::metadata(&fetch_head)?.modified()?;
+                last = Some(mtime.duration_since(UNIX_EPOCH)?.as_secs() as i64);
+            }
+        }
+        let name = ostr!(remote.name());
+        for reference in self
+            .repo
+            .references_glob(&format!("refs/remotes/{}/*", name))?
+        {
+            let reference = reference?;
+            let Ok(reflog) = self.repo.reflog(ostr!(reference.name())) else {
+                continue;
+            };
+            // The newest entry comes first.
+            let fetched = reflog.iter().find(|entry| {
+                entry
+                    .message()
+                    .is_some_and(|m| m.starts_with("fetch") || m.starts_with("pull"))
+            });
+            if let Some(entry) = fetched {
+                last = last.max(Some(entry.committer().when().seconds()));
+            }
+        }
+        Ok(last)
+    }
+
+    /// Check if tags are synced with the remote, if requested or by `remote.<name>.pruneTags`
+    /// or `fetch.pruneTags`, but never if `remote.<name>.tagOpt` is `--no-tags`.
+    pub fn syncs_tags(&self, remote: &Remote, requested: bool) -> Result<bool, Box<dyn Error>> {
+        let name = ostr!(remote.name());
+        if self
+            .config
+            .get_string(&format!("remote.{}.tagOpt", name))
+            .is_ok_and(|opt| opt == "--no-tags")
+        {
+            return Ok(false);
+        }
+        let prune_tags = match self.config.get_bool(&format!("remote.{}.pruneTags", name)) {
+            Ok(prune_tags) => prune_tags,
+            Err(_) => self.config.get_bool("fetch.pruneTags").unwrap_or(false),
+        };
+        Ok(requested || prune_tags)
+    }
+
+    /// Compare local tags with the tags of the remote fetched just before. Local tags not
+    /// on the remote, including tags never pushed, are deleted and kept as
+    /// `refs/hubsync/tags/<date>/<name>`. Tags that differ are updated only if clobber.
+    /// With dry_run, nothing is changed but the updates are still returned.
+    pub fn sync_tags(
+        &self,
+        remote: &mut Remote,
+        clobber: bool,
+        dry_run: bool,
+    ) -> Result<TagSync, Box<dyn Error>> {
+        let mut remote_tags = HashMap::new();
+        for head in remote.list()? {
+            if head.name().starts_with("refs/tags/") && !head.name().ends_with("^{}") {
+                remote_tags.insert(head.name().to_string(), head.oid());
+            }
+        }
+        let mut sync = TagSync::default();
+        let mut clobbered = vec![];
+        for result in self.repo.references_glob("refs/tags/*")? {
+            let mut refer = result?;
+            let refname = ostr!(refer.name()).to_string();
+            let Some(local) = refer.target() else {
+                continue;
+            };
+            match remote_tags.get(&refname) {
+                None => {
+                    if !dry_run {
+                        self.delete_tag(&mut refer, local)?;
+                    }
+                    let mut update = RefUpdate::new(&refname, local, Oid::zero());
+                    update.kind = RefKind::Tag;
+                    update.src = "(none)".to_string();
+                    update.dst = prefix_stripped(&refname, "refs/tags/").to_string();
+                    sync.updates.push(update);
+                }
+                Some(&remote) if remote != local => {
+                    let mismatch = TagMismatch {
+                        name: prefix_stripped(&refname, "refs/tags/").to_string(),
+                        local,
+                        remote,
+                    };
+                    if clobber {
+                        clobbered.push(format!("+{}:{}", refname, refname));
+                        sync.clobbered.push(mismatch);
+                    } else {
+                        sync.mismatches.push(mismatch);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        if !clobbered.is_empty() && !dry_run {
+            // Without auto-follow, as it never overwrites existing tags.
+            let fetched =
+                self.fetch_refspecs(remote, &clobbered, FetchPrune::Off, AutotagOption::None)?;
+            sync.updates.extend(fetched.updates);
+        }
+        Ok(sync)
+    }
+
+    /// Delete the tag at the oid, keeping it in the trash of tags.
+    fn delete_tag(&self, tag: &mut Reference, oid: Oid) -> Result<(), Box<dyn Error>> {
+        let refname = ostr!(tag.name()).to_string();
+        let name = prefix_stripped(&refname, "refs/tags/");
+        let backup = self.backup_refname(TAG_TRASH_PREFIX, &date_string(days_since_epoch()), name);
+        self.repo.reference(
+            &backup,
+            oid,
+            false,
+            &format!("hubsync: delete tag {}", name),
+        )?;
+        tag.delete()?;
+        self.journal.record(&JournalEntry::Delete {
+            refname,
+            oid,
+            backup,
+            config: vec![],
+        })
+    }
+
+    /// Check if the index or the worktree has changes, ignoring untracked files.
+    pub fn is_dirty(&self) -> Result<bool, Box<dyn Error>> {
+        is_dirty(&self.repo)
+    }
+
+    /// Path of the other worktree where the branch is checked out.
+    pub fn worktree_of(&self, branch: &Branch) -> Result<Option<&Path>, Box<dyn Error>> {
+        let worktrees = match self.worktrees.get() {
+            Some(worktrees) => worktrees,
+            None => {
+                let worktrees = self.other_worktrees()?;
+                self.worktrees.get_or_init(|| worktrees)
+            }
+        };
+        Ok(worktrees
+            .get(ostr!(branch.get().name()))
+            .map(|path| path.as_path()))
+    }
+
+    /// Branches checked out in the other worktrees, by refname, with the worktree path.
+    ///
+    /// Like `git branch -d`, a worktree whose directory is missing still holds its branch,
+    /// unless `git worktree prune` would remove it as it is not locked.
+    fn other_worktrees(&self) -> Result<HashMap<String, PathBuf>, Box<dyn Error>> {
+        let mut repos = vec![];
+        let mut worktrees = HashMap::new();
+        if self.repo.is_worktree() {
+            repos.push(Repository::open(self.repo.commondir())?);
+        }
+        for name in self.repo.worktrees()?.iter().flatten() {
+            let worktree = self.repo.find_worktree(name)?;
+            if worktree.validate().is_ok() {
+                repos.push(Repository::open_from_worktree(&worktree)?);
+            } else if let WorktreeLockStatus::Locked(_) = worktree.is_locked()? {
+                let head = self
+                    .repo
+                    .commondir()
+                    .join("worktrees")
+                    .join(name)
+                    .join("HEAD");
+                if let Some(refname) = fs::read_to_string(head)?.trim_end().strip_prefix("ref: ") {
+                    worktrees.insert(refname.to_string(), worktree.path().to_path_buf());
+                }
+            }
+        }
+        let current = self
+            .repo
+            .workdir()
+            .and_then(|path| path.canonicalize().ok());
+        for repo in repos {
+            let Some(path) = repo.workdir().and_then(|path| path.canonicalize().ok()) else {
+                continue;
+            };
+            if Some(&path) == current.as_ref() {
+                continue;
+            }
+            if let Some(name) = repo.find_reference("HEAD")?.symbolic_target() {
+                worktrees.insert(name.to_string(), path);
+            }
+        }
+        Ok(worktrees)
+    }
+
+    pub fn is_worktree_dirty(&self, path: &Path) -> Result<bool, Box<dyn Error>> {
+        is_dirty(&Repository::open(path)?)
+    }
+
+    /// Fast-forward the branch checked out in the other worktree.
+    pub fn fastforward_worktree(
+        &self,
+        path: &Path,
+        branch: &mut Branch,
+        upstream: &Branch,
+    ) -> Result<(), Box<dyn Error>> {
+        let repo = Repository::open(path)?;
+        let commit = repo.find_commit(upstream.get().peel_to_commit()?.id())?;
+        repo.checkout_tree(commit.as_object(), None)?;
+        let (updated, old, new) = self.set_target(branch, upstream)?;
+        let refname = ostr!(updated.get().name()).to_string();
+        self.journal.record(&JournalEntry::Worktree {
+            refname,
+            old,
+            new,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn stash(&self) -> Result<Oid, Box<dyn Error>> {
+        // Stash API requires mutable repository.
+        let mut repo = Repository::open(self.repo.path())?;
+        let signature = self
+            .repo
+            .signature()
+            .or_else(|_| Signature::now("git-hubsync", "git-hubsync@localhost"))?;
+        Ok(repo.stash_save(&signature, "git-hubsync autostash", None)?)
+    }
+
+    pub fn unstash(&self) -> Result<(), Box<dyn Error>> {
+        let mut repo = Repository::open(self.repo.path())?;
+        let mut opts = StashApplyOptions::new();
+        opts.reinstantiate_index();
+        Ok(repo.stash_pop(0, Some(&mut opts))?)
     }
 
     pub fn local_branches(&self) -> Result<Vec<Branch<'_>>, Box<dyn Error>> {
@@ -211,8 +901,10 @@ impl Git {
     ) -> Result<(), Box<dyn Error>> {
         self.repo
             .checkout_tree(&upstream.get().peel(ObjectType::Commit)?, None)?;
-        self.update_ref(branch, upstream)?;
-        Ok(())
+        let (updated, old, new) = self.set_target(branch, upstream)?;
+        let refname = ostr!(updated.get().name()).to_string();
+        self.journal
+            .record(&JournalEntry::FastForward { refname, old, new })
     }
 
     pub fn new_range(
@@ -232,6 +924,19 @@ impl Git {
         branch: &mut Branch<'a>,
         remote_branch: &Branch,
     ) -> Result<Branch<'a>, Box<dyn Error>> {
+        let (updated, old, new) = self.set_target(branch, remote_branch)?;
+        let refname = ostr!(updated.get().name()).to_string();
+        self.journal
+            .record(&JournalEntry::Update { refname, old, new })?;
+        Ok(updated)
+    }
+
+    /// Move the branch to the remote branch, returning the updated branch, old and new OID.
+    fn set_target<'a>(
+        &self,
+        branch: &mut Branch<'a>,
+        remote_branch: &Branch,
+    ) -> Result<(Branch<'a>, Oid, Oid), Box<dyn Error>> {
         let rc = self
             .repo
             .reference_to_annotated_commit(remote_branch.get())?;
@@ -240,8 +945,440 @@ impl Git {
             ostr!(branch.name()?),
             ostr!(remote_branch.name()?)
         );
+        let old = branch.get().peel_to_commit()?.id();
         let refer = branch.get_mut().set_target(rc.id(), &msg)?;
-        Ok(Branch::wrap(refer))
+        Ok((Branch::wrap(refer), old, rc.id()))
+    }
+
+    /// Rebase the current branch onto the upstream, returning the new tip,
+    /// or None if aborted because of conflicts.
+    ///
+    /// The rebase is aborted rather than left in progress, since `git rebase --continue`
+    /// cannot continue a rebase started by libgit2.
+    pub fn rebase(
+        &self,
+        branch: &Branch,
+        upstream: &Branch,
+    ) -> Result<Option<Oid>, Box<dyn Error>> {
+        let signature = self.repo.signature()?;
+        let refname = ostr!(branch.get().name()).to_string();
+        let old = branch.get().peel_to_commit()?.id();
+        let local = self.repo.reference_to_annotated_commit(branch.get())?;
+        let onto = self.repo.reference_to_annotated_commit(upstream.get())?;
+        let mut rebase = self.repo.rebase(Some(&local), Some(&onto), None, None)?;
+        while let Some(operation) = rebase.next() {
+            operation?;
+            if self.repo.index()?.has_conflicts() {
+                rebase.abort()?;
+                return Ok(None);
+            }
+            match rebase.commit(None, &signature, None) {
+                Ok(_) => {}
+                // Already in the upstream.
+                Err(e) if e.code() == ErrorCode::Applied => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        rebase.finish(Some(&signature))?;
+        let new = self.repo.refname_to_id(&refname)?;
+        self.journal
+            .record(&JournalEntry::FastForward { refname, old, new })?;
+        Ok(Some(new))
+    }
+
+    /// Rebase the branch onto the upstream in memory and move the ref, without
+    /// touching any worktree. Returns the new tip, or None if aborted because of conflicts.
+    pub fn rebase_ref(
+        &self,
+        branch: &mut Branch,
+        upstream: &Branch,
+    ) -> Result<Option<Oid>, Box<dyn Error>> {
+        let signature = self.repo.signature()?;
+        let local = self.repo.reference_to_annotated_commit(branch.get())?;
+        let onto = self.repo.reference_to_annotated_commit(upstream.get())?;
+        let mut opts = RebaseOptions::new();
+        opts.inmemory(true);
+        let mut rebase = self
+            .repo
+            .rebase(Some(&local), Some(&onto), None, Some(&mut opts))?;
+        let mut new = onto.id();
+        while let Some(operation) = rebase.next() {
+            operation?;
+            if rebase.inmemory_index()?.has_conflicts() {
+                rebase.abort()?;
+                return Ok(None);
+            }
+            match rebase.commit(None, &signature, None) {
+                Ok(oid) => new = oid,
+                Err(e) if e.code() == ErrorCode::Applied => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        rebase.finish(Some(&signature))?;
+        let old = branch.get().peel_to_commit()?.id();
+        let msg = format!(
+            "rebase: {} onto {}",
+            ostr!(branch.name()?),
+            ostr!(upstream.name()?)
+        );
+        let refer = branch.get_mut().set_target(new, &msg)?;
+        self.journal.record(&JournalEntry::Update {
+            refname: ostr!(refer.name()).to_string(),
+            old,
+            new,
+        })?;
+        Ok(Some(new))
+    }
+
+    /// Merge the upstream into the current branch with a merge commit, returning
+    /// the merge commit, or None if stopped with conflicts.
+    pub fn merge(&self, branch: &Branch, upstream: &Branch) -> Result<Option<Oid>, Box<dyn Error>> {
+        let signature = self.repo.signature()?;
+        let refname = ostr!(branch.get().name()).to_string();
+        let head = branch.get().peel_to_commit()?;
+        let theirs = upstream.get().peel_to_commit()?;
+        let annotated = self.repo.reference_to_annotated_commit(upstream.get())?;
+        self.repo.merge(&[&annotated], None, None)?;
+        let mut index = self.repo.index()?;
+        if index.has_conflicts() {
+            return Ok(None);
+        }
+        let tree = self.repo.find_tree(index.write_tree()?)?;
+        let msg = format!(
+            "Merge remote-tracking branch '{}' into {}",
+            ostr!(upstream.name()?),
+            ostr!(branch.name()?)
+        );
+        let new = self.repo.commit(
+            Some(&refname),
+            &signature,
+            &signature,
+            &msg,
+            &tree,
+            &[&head, &theirs],
+        )?;
+        self.repo.cleanup_state()?;
+        self.journal.record(&JournalEntry::FastForward {
+            refname,
+            old: head.id(),
+            new,
+        })?;
+        Ok(Some(new))
+    }
+
+    /// `branch.<name>.rebase` or `pull.rebase`, None if neither is set.
+    pub fn pull_rebase(&self, branch_name: &str) -> Result<Option<PullRebase>, Box<dyn Error>> {
+        for key in [
+            format!("branch.{}.rebase", branch_name),
+            "pull.rebase".to_string(),
+        ] {
+            match self.config.get_string(&key) {
+                Ok(value) => {
+                    return match value.to_lowercase().as_str() {
+                        "true" | "yes" | "on" | "1" => Ok(Some(PullRebase::Rebase)),
+                        "false" | "no" | "off" | "0" | "" => Ok(Some(PullRebase::Merge)),
+                        "merges" | "m" | "interactive" | "i" => {
+                            Ok(Some(PullRebase::Unsupported(format!("{}={}", key, value))))
+                        }
+                        _ => Err(
+                            GitError::new(format!("invalid value for {}: {}", key, value)).into(),
+                        ),
+                    };
+                }
+                Err(e) if e.code() == ErrorCode::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check if the OID is, or is an ancestor of, a previous value of the upstream
+    /// recorded in its reflog.
+    pub fn was_upstream(&self, upstream: &Branch, oid: Oid) -> Result<bool, Box<dyn Error>> {
+        let reflog = match self.repo.reflog(ostr!(upstream.get().name())) {
+            Ok(reflog) => reflog,
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in reflog.iter() {
+            for previous in [entry.id_old(), entry.id_new()] {
+                if previous.is_zero() {
+                    continue;
+                }
+                if previous == oid || self.repo.graph_descendant_of(previous, oid)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reset the branch to the upstream, along with the worktree if checkout, keeping
+    /// the old tip as `refs/hubsync/reset/<date>/<name>`. Returns the ref of the old tip.
+    pub fn reset_branch(
+        &self,
+        branch: &mut Branch,
+        upstream: &Branch,
+        checkout: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let name = ostr!(branch.name()?).to_string();
+        let saved = self.backup_refname(RESET_PREFIX, &date_string(days_since_epoch()), &name);
+        let old = branch.get().peel_to_commit()?.id();
+        self.repo.reference(
+            &saved,
+            old,
+            false,
+            &format!("hubsync: reset branch {}", name),
+        )?;
+        if checkout {
+            self.repo
+                .checkout_tree(&upstream.get().peel(ObjectType::Commit)?, None)?;
+        }
+        let (updated, old, new) = self.set_target(branch, upstream)?;
+        let refname = ostr!(updated.get().name()).to_string();
+        self.journal.record(&if checkout {
+            JournalEntry::FastForward { refname, old, new }
+        } else {
+            JournalEntry::Update { refname, old, new }
+        })?;
+        Ok(saved)
+    }
+
+    /// Delete the branch, keeping its tip and upstream config in the trash.
+    pub fn delete_branch(&self, branch: &mut Branch) -> Result<Backup, Box<dyn Error>> {
+        let name = ostr!(branch.name()?).to_string();
+        let date = date_string(days_since_epoch());
+        let refname = self.backup_refname(TRASH_PREFIX, &date, &name);
+        let backup = Backup::from_refname(&refname, branch.get().peel_to_commit()?.id())
+            .ok_or_else(|| GitError::new(format!("invalid backup ref {}", refname)))?;
+        self.repo.reference(
+            &backup.refname,
+            backup.oid,
+            false,
+            &format!("hubsync: delete branch {}", backup.name),
+        )?;
+        let mut config = self.repo.config()?.open_level(ConfigLevel::Local)?;
+        let mut branch_config = vec![];
+        for key in BRANCH_CONFIG_KEYS {
+            let _ = config.remove(&backup.config_key(key));
+            let branch_key = format!("branch.{}.{}", backup.name, key);
+            if let Ok(value) = self.config.get_string(&branch_key) {
+                config.set_str(&backup.config_key(key), &value)?;
+                branch_config.push((branch_key, value));
+            }
+        }
+        let refname = ostr!(branch.get().name()).to_string();
+        branch.delete()?;
+        self.journal.record(&JournalEntry::Delete {
+            refname,
+            oid: backup.oid,
+            backup: backup.refname.clone(),
+            config: branch_config,
+        })?;
+        Ok(backup)
+    }
+
+    /// Deleted branches in the trash, oldest first.
+    pub fn backups(&self) -> Result<Vec<Backup>, Box<dyn Error>> {
+        let mut backups = vec![];
+        for result in self.repo.references()? {
+            let refer = result?;
+            if let Some(oid) = refer.target()
+                && let Some(backup) = Backup::from_refname(ostr!(refer.name()), oid)
+            {
+                backups.push(backup);
+            }
+        }
+        backups.sort_by(|b1, b2| (&b1.date, b1.seq).cmp(&(&b2.date, b2.seq)));
+        Ok(backups)
+    }
+
+    /// Refname `<prefix><date>/<name>` for a new backup, or `<prefix><date>.<n>/<name>`
+    /// if the name was already backed up on the date, so that no backup is overwritten.
+    fn backup_refname(&self, prefix: &str, date: &str, name: &str) -> String {
+        let mut refname = format!("{}{}/{}", prefix, date, name);
+        let mut seq = 1;
+        while self.repo.find_reference(&refname).is_ok() {
+            seq += 1;
+            refname = format!("{}{}.{}/{}", prefix, date, seq, name);
+        }
+        refname
+    }
+
+    /// Recreate the branch from its latest backup, with its upstream config.
+    pub fn restore_branch(&self, name: &str) -> Result<Backup, Box<dyn Error>> {
+        let backup = self
+            .backups()?
+            .into_iter()
+            .rev()
+            .find(|backup| backup.name == name)
+            .ok_or_else(|| GitError::new(format!("no deleted branch '{}' in the trash", name)))?;
+        let commit = self.repo.find_commit(backup.oid)?;
+        self.repo.branch(name, &commit, false)?;
+        let mut config = self.repo.config()?.open_level(ConfigLevel::Local)?;
+        for key in BRANCH_CONFIG_KEYS {
+            if let Ok(value) = self.config.get_string(&backup.config_key(key)) {
+                config.set_str(&format!("branch.{}.{}", name, key), &value)?;
+            }
+        }
+        self.remove_backup(&backup)?;
+        Ok(backup)
+    }
+
+    /// Remove backups older than `hubsync.trashRetention` days, 0 to keep forever.
+    /// Tips replaced by reset and deleted tags are removed too, but not returned.
+    pub fn expire_backups(&self) -> Result<Vec<Backup>, Box<dyn Error>> {
+        let days = match self.config.get_i64("hubsync.trashRetention") {
+            Ok(days) => days,
+            Err(e) if e.code() == ErrorCode::NotFound => DEFAULT_TRASH_RETENTION,
+            Err(e) => return Err(e.into()),
+        };
+        if days <= 0 {
+            return Ok(vec![]);
+        }
+        let expiry = date_string(days_since_epoch() - days);
+        let mut expired = vec![];
+        for backup in self.backups()? {
+            if backup.date < expiry {
+                self.remove_backup(&backup)?;
+                expired.push(backup);
+            }
+        }
+        for result in self.repo.references()? {
+            let mut refer = result?;
+            let refname = ostr!(refer.name());
+            if let Some((date, _)) = refname
+                .strip_prefix(RESET_PREFIX)
+                .or_else(|| refname.strip_prefix(TAG_TRASH_PREFIX))
+                .and_then(|s| s.split_once('/'))
+                && date < expiry.as_str()
+            {
+                refer.delete()?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Revert the last sync recorded in the journal, refusing if anything has moved since.
+    pub fn undo(&self) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let entries = self
+            .journal
+            .read()?
+            .ok_or_else(|| GitError::new("nothing to undo".to_string()))?;
+        for entry in &entries {
+            self.check_undo(entry)?;
+        }
+        for entry in entries.iter().rev() {
+            self.undo_entry(entry)?;
+        }
+        self.journal.remove()?;
+        Ok(entries)
+    }
+
+    fn check_undo(&self, entry: &JournalEntry) -> Result<(), Box<dyn Error>> {
+        match entry {
+            JournalEntry::Update { refname, new, .. }
+            | JournalEntry::FastForward { refname, new, .. }
+            | JournalEntry::Worktree { refname, new, .. } => {
+                if self.repo.refname_to_id(refname).ok() != Some(*new) {
+                    return Err(GitError::new(format!(
+                        "refusing to undo, {} has moved since the sync",
+                        refname
+                    ))
+                    .into());
+                }
+            }
+            JournalEntry::Head { new, .. } => {
+                if &self.head_target()? != new {
+                    return Err(GitError::new(
+                        "refusing to undo, HEAD has moved since the sync".to_string(),
+                    )
+                    .into());
+                }
+            }
+            JournalEntry::Delete { refname, .. } => {
+                if self.repo.find_reference(refname).is_ok() {
+                    return Err(GitError::new(format!(
+                        "refusing to undo, {} exists again",
+                        refname
+                    ))
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn undo_entry(&self, entry: &JournalEntry) -> Result<(), Box<dyn Error>> {
+        let msg = "hubsync: undo";
+        match entry {
+            JournalEntry::Update { refname, old, .. } => {
+                self.repo.reference(refname, *old, true, msg)?;
+            }
+            JournalEntry::FastForward { refname, old, .. } => {
+                self.repo
+                    .checkout_tree(self.repo.find_commit(*old)?.as_object(), None)?;
+                self.repo.reference(refname, *old, true, msg)?;
+            }
+            JournalEntry::Worktree {
+                refname, old, path, ..
+            } => {
+                let repo = Repository::open(path)?;
+                repo.checkout_tree(repo.find_commit(*old)?.as_object(), None)?;
+                self.repo.reference(refname, *old, true, msg)?;
+            }
+            JournalEntry::Head { old, .. } => {
+                if let Ok(oid) = Oid::from_str(old) {
+                    self.repo
+                        .checkout_tree(self.repo.find_commit(oid)?.as_object(), None)?;
+                    self.repo.set_head_detached(oid)?;
+                } else {
+                    let commit = self.repo.find_reference(old)?.peel_to_commit()?;
+                    self.repo.checkout_tree(commit.as_object(), None)?;
+                    self.repo.set_head(old)?;
+                }
+            }
+            JournalEntry::Delete {
+                refname,
+                oid,
+                backup,
+                config,
+            } => {
+                self.repo.reference(refname, *oid, false, msg)?;
+                let mut local = self.repo.config()?.open_level(ConfigLevel::Local)?;
+                for (key, value) in config {
+                    local.set_str(key, value)?;
+                }
+                if let Ok(mut refer) = self.repo.find_reference(backup)
+                    && refer.target() == Some(*oid)
+                {
+                    match Backup::from_refname(backup, *oid) {
+                        Some(backup) => self.remove_backup(&backup)?,
+                        None => refer.delete()?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_backup(&self, backup: &Backup) -> Result<(), Box<dyn Error>> {
+        self.repo.find_reference(&backup.refname)?.delete()?;
+        let mut config = self.repo.config()?.open_level(ConfigLevel::Local)?;
+        for key in BRANCH_CONFIG_KEYS {
+            let _ = config.remove(&backup.config_key(key));
+        }
+        Ok(())
+    }
+
+    /// Glob patterns of protected branches from `hubsync.protect`.
+    pub fn protect_patterns(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut patterns = vec![];
+        let mut entries = self.config.multivar("hubsync.protect", None)?;
+        while let Some(entry) = entries.next() {
+            patterns.push(ostr!(entry?.value()).to_string());
+        }
+        Ok(patterns)
     }
 
     pub fn only_one_remote(&self) -> Result<Option<Remote<'_>>, Box<dyn Error>> {
@@ -255,6 +1392,21 @@ impl Git {
         Ok(None)
     }
 
+    /// Remote from `remote.pushdefault` or `checkout.defaultRemote`, used without current branch.
+    pub fn configured_remote(&self) -> Result<Remote<'_>, Box<dyn Error>> {
+        let name = if let Ok(name) = self.config.get_string("remote.pushdefault") {
+            name
+        } else if let Ok(name) = self.config.get_string("checkout.defaultRemote") {
+            name
+        } else {
+            return Err(GitError::new(
+                "Unable to find default remote without current branch".to_string(),
+            )
+            .into());
+        };
+        Ok(self.repo.find_remote(&name)?)
+    }
+
     pub fn remote(&self, branch: &Branch) -> Result<Remote<'_>, Box<dyn Error>> {
         let branch_name = ostr!(branch.get().name());
         let name = if let Ok(buf) = self.repo.branch_upstream_remote(branch_name) {
@@ -292,6 +1444,65 @@ impl Git {
     }
 }
 
+/// Trust the CA certificates of the settings, in addition to the default ones.
+/// The trust is process-wide and permanent, as libgit2 cannot remove certificates
+/// from its store.
+///
+/// Only libgit2 built with OpenSSL supports certificate locations. Other platforms use
+/// SecureTransport or WinHTTP, which verify against the certificate store of the system.
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn set_ssl_cert_locations(tls: &http::Tls) -> Result<(), Box<dyn Error>> {
+    // SAFETY: with OpenSSL, libgit2 only loads the locations into the certificate store
+    // of its shared SSL context, which OpenSSL locks, so other threads using libgit2 at
+    // the same time see the store either before or after the certificates are added.
+    unsafe {
+        if let Some(ca_info) = &tls.ca_info {
+            git2::opts::set_ssl_cert_file(ca_info)?;
+        }
+        if let Some(ca_path) = &tls.ca_path {
+            git2::opts::set_ssl_cert_dir(ca_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(windows, target_vendor = "apple"))]
+fn set_ssl_cert_locations(_tls: &http::Tls) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+fn is_dirty(repo: &Repository) -> Result<bool, Box<dyn Error>> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    Ok(!repo.statuses(Some(&mut opts))?.is_empty())
+}
+
+fn seconds_since_epoch() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn days_since_epoch() -> i64 {
+    seconds_since_epoch() / 86400
+}
+
+/// Format days since the Unix epoch as "YYYY-MM-DD" of the proleptic Gregorian calendar.
+fn date_string(days: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
 pub fn is_branch_same(b1: &Branch, b2: &Branch) -> Result<bool, Box<dyn Error>> {
     let n1 = b1.name_bytes()?;
     let n2 = b2.name_bytes()?;
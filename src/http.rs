@@ -0,0 +1,300 @@
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use git2::Config;
+use url::Url;
+
+/// Value of `http.<url>.<key>` best matching the URL, or of `http.<key>`, like
+/// `git config --get-urlmatch`. A key without value is returned as "true".
+pub fn config_for_url(
+    config: &Config,
+    key: &str,
+    url: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(lookup_url(config, "http", key, url)?.map(|(_, value)| value))
+}
+
+/// How specific a config URL matched, as the length of the path and whether the user
+/// name matched. None for a key without URL, which matches any URL.
+pub type Specificity = Option<(usize, bool)>;
+
+/// Value of `<section>.<url>.<key>` best matching the URL, or of `<section>.<key>`,
+/// with the specificity of the match.
+///
+/// A longer matching path takes precedence, then a matching user name, then the
+/// config read last.
+pub fn lookup_url(
+    config: &Config,
+    section: &str,
+    key: &str,
+    url: &str,
+) -> Result<Option<(Specificity, String)>, Box<dyn Error>> {
+    let Ok(target) = Url::parse(url) else {
+        return Ok(config
+            .get_string(&format!("{}.{}", section, key))
+            .ok()
+            .map(|value| (None, value)));
+    };
+    let prefix = format!("{}.", section);
+    let mut best: Option<(Specificity, String)> = None;
+    let mut entries = config.entries(Some(&format!("^{}\\.", section)))?;
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let Some(name) = entry.name().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+        let (pattern, var) = match name.rsplit_once('.') {
+            Some((pattern, var)) => (Some(pattern), var),
+            None => (None, name),
+        };
+        if !var.eq_ignore_ascii_case(key) {
+            continue;
+        }
+        let score = match pattern {
+            None => None,
+            Some(pattern) => match Url::parse(pattern)
+                .ok()
+                .and_then(|p| url_match(&p, &target))
+            {
+                Some(score) => Some(score),
+                None => continue,
+            },
+        };
+        if best.as_ref().is_none_or(|(best, _)| score >= *best) {
+            best = Some((score, entry.value().unwrap_or("true").to_string()));
+        }
+    }
+    Ok(best)
+}
+
+/// Boolean of `http.<url>.<key>` best matching the URL, or of `http.<key>`.
+pub fn config_bool_for_url(
+    config: &Config,
+    key: &str,
+    url: &str,
+) -> Result<Option<bool>, Box<dyn Error>> {
+    let Some(value) = config_for_url(config, key, url)? else {
+        return Ok(None);
+    };
+    Ok(Some(Config::parse_bool(value.as_str())?))
+}
+
+/// Specificity of the config URL for the URL, as the length of the matched path and
+/// whether the user name is matched, or None if it does not match.
+fn url_match(pattern: &Url, target: &Url) -> Option<(usize, bool)> {
+    if pattern.scheme() != target.scheme()
+        || pattern.port_or_known_default() != target.port_or_known_default()
+    {
+        return None;
+    }
+    let host = pattern.host_str()?;
+    let target_host = target.host_str()?;
+    let labels = host.split('.').collect::<Vec<_>>();
+    let target_labels = target_host.split('.').collect::<Vec<_>>();
+    if labels.len() != target_labels.len()
+        || !labels
+            .iter()
+            .zip(&target_labels)
+            .all(|(l, t)| *l == "*" || l.eq_ignore_ascii_case(t))
+    {
+        return None;
+    }
+    let user = !pattern.username().is_empty();
+    if user && pattern.username() != target.username() {
+        return None;
+    }
+    let path = pattern.path().trim_end_matches('/');
+    let rest = target.path().strip_prefix(path)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    Some((path.len(), user))
+}
+
+/// Proxy for the URL of the remote, or None to connect directly.
+///
+/// Like git, it is `remote.<name>.proxy`, `http.<url>.proxy` or `http.proxy`, then
+/// detected from `https_proxy`, `http_proxy` or `all_proxy` environment variables.
+/// An empty value disables the proxy, and hosts in `no_proxy` are connected directly.
+/// Only HTTP(S) remotes use a proxy.
+pub fn proxy_for(
+    config: &Config,
+    remote: &str,
+    url: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let Ok(target) = Url::parse(url) else {
+        return Ok(None);
+    };
+    if target.scheme() != "http" && target.scheme() != "https" {
+        return Ok(None);
+    }
+    let proxy = match config.get_string(&format!("remote.{}.proxy", remote)) {
+        Ok(proxy) => Some(proxy),
+        Err(_) => config_for_url(config, "proxy", url)?,
+    };
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => {
+            let names: &[&str] = if target.scheme() == "https" {
+                &["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]
+            } else {
+                // Like curl, HTTP_PROXY is ignored as it may be set by CGI.
+                &["http_proxy", "all_proxy", "ALL_PROXY"]
+            };
+            let Some(proxy) = names.iter().find_map(|name| env_var(name)) else {
+                return Ok(None);
+            };
+            proxy
+        }
+    };
+    if proxy.is_empty() || target.host_str().is_some_and(is_no_proxy) {
+        return Ok(None);
+    }
+    if proxy.contains("://") {
+        Ok(Some(proxy))
+    } else {
+        Ok(Some(format!("http://{}", proxy)))
+    }
+}
+
+/// TLS settings for an HTTPS remote.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tls {
+    /// Verify the certificate of the server, otherwise accept any certificate.
+    pub verify: bool,
+    /// File of CA certificates to trust.
+    pub ca_info: Option<PathBuf>,
+    /// Directory of CA certificates to trust.
+    pub ca_path: Option<PathBuf>,
+}
+
+/// TLS settings for the URL, like git: `http.<url>.sslVerify`, `http.<url>.sslCAInfo`
+/// and `http.<url>.sslCAPath` (or without `<url>`), overridden by `GIT_SSL_NO_VERIFY`,
+/// `GIT_SSL_CAINFO` and `GIT_SSL_CAPATH` environment variables. None unless HTTPS.
+pub fn tls_for(config: &Config, url: &str) -> Result<Option<Tls>, Box<dyn Error>> {
+    if !Url::parse(url).is_ok_and(|target| target.scheme() == "https") {
+        return Ok(None);
+    }
+    let verify = env::var_os("GIT_SSL_NO_VERIFY").is_none()
+        && config_bool_for_url(config, "sslVerify", url)?.unwrap_or(true);
+    let path = |var: &str, key: &str| -> Result<Option<PathBuf>, Box<dyn Error>> {
+        Ok(env_var(var)
+            .or(config_for_url(config, key, url)?)
+            .filter(|path| !path.is_empty())
+            .map(|path| expand_home(&path)))
+    };
+    Ok(Some(Tls {
+        verify,
+        ca_info: path("GIT_SSL_CAINFO", "sslCAInfo")?,
+        ca_path: path("GIT_SSL_CAPATH", "sslCAPath")?,
+    }))
+}
+
+/// Expand leading "~/" of the path to the home directory, as git does for paths.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Check if the host is listed in `no_proxy`, as a suffix on a domain boundary or "*".
+fn is_no_proxy(host: &str) -> bool {
+    let Some(no_proxy) = env_var("no_proxy").or_else(|| env_var("NO_PROXY")) else {
+        return false;
+    };
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*"
+            || (!entry.is_empty()
+                && (host.eq_ignore_ascii_case(entry)
+                    || host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", entry.to_ascii_lowercase()))))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use git2::Config;
+
+    use super::proxy_for;
+
+    /// New empty directory under `git-hubsync-test`, unique to the test.
+    pub(crate) fn temp_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut dir = env::temp_dir();
+        dir.push("git-hubsync-test");
+        dir.push(format!(
+            "config-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Config of a file in a new directory, with only the entries.
+    pub(crate) fn config_with(entries: &[(&str, &str)]) -> Config {
+        let mut config = Config::open(&temp_dir().join("config")).unwrap();
+        for (key, value) in entries {
+            config.set_str(key, value).unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn test_proxy_for() {
+        let config = config_with(&[
+            ("http.proxy", "proxy.example.com:3128"),
+            (
+                "http.https://*.example.org/internal.proxy",
+                "http://internal-proxy:8080",
+            ),
+            (
+                "http.https://git.example.org/internal/team.proxy",
+                "http://team-proxy:8080",
+            ),
+            ("remote.direct.proxy", ""),
+        ]);
+        let proxy = |remote, url| proxy_for(&config, remote, url).unwrap();
+
+        assert_eq!(
+            proxy("origin", "https://github.com/iquiw/git-hubsync"),
+            Some("http://proxy.example.com:3128".to_string())
+        );
+        assert_eq!(
+            proxy("origin", "https://git.example.org/internal/repo.git"),
+            Some("http://internal-proxy:8080".to_string())
+        );
+        assert_eq!(
+            proxy("origin", "https://git.example.org/internal/team/repo.git"),
+            Some("http://team-proxy:8080".to_string())
+        );
+        assert_eq!(
+            proxy("origin", "https://git.example.org/internal-other/repo.git"),
+            Some("http://proxy.example.com:3128".to_string())
+        );
+        assert_eq!(
+            proxy("direct", "https://github.com/iquiw/git-hubsync"),
+            None
+        );
+        assert_eq!(
+            proxy("origin", "git@github.com:iquiw/git-hubsync.git"),
+            None
+        );
+        assert_eq!(proxy("origin", "../origin.git"), None);
+    }
+}
@@ -1,187 +1,820 @@
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
 
-use colored::Colorize;
-use git2::{self, Branch, ErrorClass, ErrorCode, Oid, Repository};
+use git2::{self, Branch, ErrorClass, ErrorCode, Oid};
 
 use crate::err::GitError;
-use crate::git::{self, Git};
+use crate::git::{self, Divergence, Git, PullRebase, RefUpdate};
 
-enum BranchAction<'a> {
-    UpToDate,
+pub enum BranchAction<'a> {
+    UpToDate(Branch<'a>),
     Merge(Branch<'a>, Oid),
+    /// Fast-forward the branch checked out in the other worktree.
+    MergeWorktree(Branch<'a>, Oid, PathBuf),
     UpdateRef(Branch<'a>, Oid),
-    Unpushed,
+    /// Reset the branch to the force-pushed upstream, as the branch was a previous
+    /// value of the upstream and has no unique work.
+    Reset(Branch<'a>, Oid),
+    /// Rebase the diverged current branch onto the upstream.
+    Rebase(Branch<'a>, Oid),
+    /// Merge the upstream into the diverged current branch.
+    MergeCommit(Branch<'a>, Oid),
+    /// Rebase the diverged branch that is not checked out onto the upstream, in memory.
+    RebaseRef(Branch<'a>, Oid),
+    /// Not an ancestor of the upstream: ahead only, diverged or the upstream rewritten.
+    Unpushed(Branch<'a>, Divergence),
     CheckoutAndDelete,
     NoDefault,
     Delete,
+    /// Merged, but not deleted because checked out in the other worktree.
+    CheckedOut(PathBuf),
+    /// Merged current branch, but not deleted because the default branch to switch to is
+    /// checked out in the other worktree.
+    DefaultCheckedOut(PathBuf),
+    /// Merged, but not deleted because protected by `hubsync.protect`.
+    Protected,
     Unmerged,
 }
 
+impl BranchAction<'_> {
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            BranchAction::UpToDate(_) => ActionKind::UpToDate,
+            BranchAction::Merge(..) => ActionKind::Merge,
+            BranchAction::MergeWorktree(..) => ActionKind::MergeWorktree,
+            BranchAction::UpdateRef(..) => ActionKind::UpdateRef,
+            BranchAction::Reset(..) => ActionKind::Reset,
+            BranchAction::Rebase(..) => ActionKind::Rebase,
+            BranchAction::MergeCommit(..) => ActionKind::MergeCommit,
+            BranchAction::RebaseRef(..) => ActionKind::RebaseRef,
+            BranchAction::Unpushed(..) => ActionKind::Unpushed,
+            BranchAction::CheckoutAndDelete => ActionKind::CheckoutAndDelete,
+            BranchAction::NoDefault => ActionKind::NoDefault,
+            BranchAction::Delete => ActionKind::Delete,
+            BranchAction::CheckedOut(_) => ActionKind::CheckedOut,
+            BranchAction::DefaultCheckedOut(_) => ActionKind::DefaultCheckedOut,
+            BranchAction::Protected => ActionKind::Protected,
+            BranchAction::Unmerged => ActionKind::Unmerged,
+        }
+    }
+
+    fn upstream(&self) -> Option<&Branch<'_>> {
+        match self {
+            BranchAction::Merge(upstream, _)
+            | BranchAction::MergeWorktree(upstream, _, _)
+            | BranchAction::UpdateRef(upstream, _)
+            | BranchAction::Reset(upstream, _)
+            | BranchAction::Rebase(upstream, _)
+            | BranchAction::MergeCommit(upstream, _)
+            | BranchAction::RebaseRef(upstream, _) => Some(upstream),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for BranchAction<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        let (tag, upstream) = match self {
-            BranchAction::UpToDate => ("up-to-date", None),
-            BranchAction::Merge(upstream, _) => ("merge ", upstream.name().unwrap_or(None)),
-            BranchAction::UpdateRef(upstream, _) => {
-                ("update-ref ", upstream.name().unwrap_or(None))
-            }
-            BranchAction::Unpushed => ("unpushed", None),
-            BranchAction::Unmerged => ("unmerged", None),
-            BranchAction::CheckoutAndDelete => ("checkout-and-delete", None),
-            BranchAction::Delete => ("delete", None),
-            BranchAction::NoDefault => ("nodefault", None),
+        write!(f, "{}", self.kind().as_str())?;
+        if let Some(Ok(Some(name))) = self.upstream().map(|upstream| upstream.name()) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Owned counterpart of `BranchAction`, as recorded in `BranchReport`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionKind {
+    UpToDate,
+    Merge,
+    MergeWorktree,
+    UpdateRef,
+    Reset,
+    Rebase,
+    MergeCommit,
+    RebaseRef,
+    Unpushed,
+    CheckoutAndDelete,
+    NoDefault,
+    Delete,
+    CheckedOut,
+    DefaultCheckedOut,
+    Protected,
+    Unmerged,
+    /// Action that changes the worktree refused because of local changes.
+    Dirty,
+    /// Rebase or RebaseRef aborted, or MergeCommit stopped because of conflicts.
+    Conflict,
+}
+
+impl ActionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionKind::UpToDate => "up-to-date",
+            ActionKind::Merge => "merge",
+            ActionKind::MergeWorktree => "merge-worktree",
+            ActionKind::UpdateRef => "update-ref",
+            ActionKind::Reset => "reset",
+            ActionKind::Rebase => "rebase",
+            ActionKind::MergeCommit => "merge-commit",
+            ActionKind::RebaseRef => "rebase-ref",
+            ActionKind::Unpushed => "unpushed",
+            ActionKind::CheckoutAndDelete => "checkout-and-delete",
+            ActionKind::NoDefault => "nodefault",
+            ActionKind::Delete => "delete",
+            ActionKind::CheckedOut => "checked-out",
+            ActionKind::DefaultCheckedOut => "default-checked-out",
+            ActionKind::Protected => "protected",
+            ActionKind::Unmerged => "unmerged",
+            ActionKind::Dirty => "dirty",
+            ActionKind::Conflict => "conflict",
+        }
+    }
+}
+
+/// How to integrate the diverged current branch with its upstream, like `git pull`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Integrate {
+    Rebase,
+    Merge,
+}
+
+/// Options of `hubsync`, the default being a plain `git hubsync`.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// Only report what would be done, without changing anything.
+    pub dry_run: bool,
+    /// Fetch the remotes first, unless fetched within `hubsync.fetchMaxAge`.
+    pub fetch: bool,
+    /// Also sync branches of the other remotes, each against its own default branch.
+    pub all_remotes: bool,
+    /// Stash local changes before updating the current branch, and reapply them after.
+    pub autostash: bool,
+    /// None to follow `branch.<name>.rebase` and `pull.rebase`.
+    pub integrate: Option<Integrate>,
+    /// Rebase diverged branches that are not checked out, in memory.
+    pub rebase_others: bool,
+    /// Sync tags with the default remote, even without `fetch.pruneTags`: local tags not on
+    /// the remote are deleted, even if never pushed.
+    pub tags: bool,
+    /// Update local tags that differ from the remote.
+    pub clobber_tags: bool,
+    /// Glob patterns of branches never to delete, in addition to `hubsync.protect`.
+    pub protect: Vec<String>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            dry_run: false,
+            fetch: true,
+            all_remotes: false,
+            autostash: false,
+            integrate: None,
+            rebase_others: false,
+            tags: false,
+            clobber_tags: false,
+            protect: vec![],
+        }
+    }
+}
+
+/// What `hubsync` did, or would do with `dry_run`.
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// None if HEAD is detached or unborn.
+    pub current_branch: Option<String>,
+    /// Fetched remotes, the default remote first.
+    pub remotes: Vec<RemoteReport>,
+    /// Local branches tracking a synced remote, in the order they were processed.
+    pub branches: Vec<BranchReport>,
+    /// Whether local changes were stashed and reapplied around the sync.
+    pub autostash: bool,
+    /// Warnings not specific to a branch, e.g. about tags or the stash.
+    pub warnings: Vec<String>,
+}
+
+/// Fetch of a remote.
+#[derive(Clone, Debug)]
+pub struct RemoteReport {
+    /// Name of the remote, e.g. "origin".
+    pub name: String,
+    /// False if the fetch was skipped by `--no-fetch` or `hubsync.fetchMaxAge`.
+    pub fetched: bool,
+    /// Remote-tracking refs and tags updated by the fetch.
+    pub updates: Vec<RefUpdate>,
+    /// Messages of the remote during fetch, already relayed to stderr.
+    pub messages: Vec<String>,
+    /// Remote default branch, e.g. "origin/main". None for an alternate remote.
+    pub remote_default: Option<String>,
+    /// Local branch of the remote default branch, e.g. "main".
+    pub local_default: Option<String>,
+    /// Remote of `local_default` if it is not this remote.
+    pub alternate_remote: Option<String>,
+}
+
+/// Action taken on a local branch.
+#[derive(Clone, Debug)]
+pub struct BranchReport {
+    /// Name of the local branch, e.g. "topic".
+    pub name: String,
+    pub action: ActionKind,
+    /// Upstream to update from, e.g. "origin/main".
+    pub upstream: Option<String>,
+    /// Tip of the branch before the sync.
+    pub old: Oid,
+    /// None if the branch is deleted.
+    pub new: Option<Oid>,
+    /// Branch checked out before deleting this branch.
+    pub checkout: Option<String>,
+    /// Other worktree where this branch is checked out.
+    pub worktree: Option<PathBuf>,
+    /// Ahead/behind counts against the upstream, for an unpushed branch.
+    pub divergence: Option<Divergence>,
+    /// Ref where the deleted branch is kept, e.g. "refs/hubsync/trash/2024-01-31/topic".
+    pub backup: Option<String>,
+    /// Why the branch was left as it is, e.g. it has diverged from the upstream.
+    pub warning: Option<String>,
+}
+
+impl BranchReport {
+    fn new(name: &str, action: &BranchAction, old: Oid) -> Self {
+        BranchReport {
+            name: name.to_string(),
+            action: action.kind(),
+            upstream: None,
+            old,
+            new: Some(old),
+            checkout: None,
+            worktree: None,
+            divergence: None,
+            backup: None,
+            warning: None,
+        }
+    }
+}
+
+/// Remotes whose branches are synced against the same remote default branch.
+struct SyncTarget<'a> {
+    remote_names: Vec<String>,
+    remote_default_branch: Branch<'a>,
+    odefault_branch: Option<Branch<'a>>,
+}
+
+impl SyncTarget<'_> {
+    fn covers(&self, remote: &git2::Remote) -> bool {
+        self.remote_names
+            .iter()
+            .any(|name| Some(name.as_str()) == remote.name())
+    }
+}
+
+/// Local changes of the worktree, checked before the first action that changes it.
+#[derive(Default)]
+struct Worktree {
+    dirty: Option<bool>,
+    stashed: bool,
+    /// Merge stopped with conflicts, so stashed changes are left in the stash.
+    conflicted: bool,
+}
+
+impl Worktree {
+    /// Check if the worktree can be changed, stashing local changes with autostash.
+    fn prepare(&mut self, git: &Git, opts: &SyncOptions) -> Result<bool, Box<dyn Error>> {
+        let dirty = match self.dirty {
+            Some(dirty) => dirty,
+            None => *self.dirty.insert(git.is_dirty()?),
         };
-        write!(f, "{}{}", tag, upstream.unwrap_or(""))
+        if !dirty || self.stashed {
+            return Ok(true);
+        }
+        if !opts.autostash {
+            return Ok(false);
+        }
+        if !opts.dry_run {
+            git.stash()?;
+        }
+        self.stashed = true;
+        Ok(true)
+    }
+}
+
+fn fetch(
+    git: &Git,
+    opts: &SyncOptions,
+    remote: &mut git2::Remote,
+) -> Result<RemoteReport, Box<dyn Error>> {
+    let fetched = if opts.fetch && !git.fetched_recently(remote)? {
+        Some(git.fetch(remote)?)
+    } else {
+        None
+    };
+    let is_fetched = fetched.is_some();
+    let fetched = fetched.unwrap_or_default();
+    Ok(RemoteReport {
+        name: ostr!(remote.name()).to_string(),
+        fetched: is_fetched,
+        updates: fetched.updates,
+        messages: fetched.messages,
+        remote_default: None,
+        local_default: None,
+        alternate_remote: None,
+    })
+}
+
+/// Delete local tags not on the remote and report tags that differ from the remote,
+/// updating them with `clobber_tags`.
+fn sync_tags(
+    git: &Git,
+    opts: &SyncOptions,
+    remote: &mut git2::Remote,
+    remote_report: &mut RemoteReport,
+    report: &mut SyncReport,
+) -> Result<(), Box<dyn Error>> {
+    let sync = git.sync_tags(remote, opts.clobber_tags, opts.dry_run)?;
+    if opts.dry_run {
+        for update in &sync.updates {
+            report.warnings.push(format!(
+                "would delete tag '{}', not on {}",
+                update.dst, remote_report.name
+            ));
+        }
+        for tag in &sync.clobbered {
+            report.warnings.push(format!(
+                "would update tag '{}' to {:.7} as on {} (was {:.7})",
+                tag.name, tag.remote, remote_report.name, tag.local
+            ));
+        }
+    } else {
+        remote_report.updates.extend(sync.updates);
+    }
+    for tag in &sync.mismatches {
+        report.warnings.push(format!(
+            "tag '{}' is {:.7} on {}, but {:.7} locally (use --clobber-tags to update it)",
+            tag.name, tag.remote, remote_report.name, tag.local
+        ));
+    }
+    Ok(())
+}
+
+/// Error of `hubsync`, with the report of what was done before the error.
+#[derive(Debug)]
+pub struct SyncError {
+    /// Remotes fetched and branches synced before the error.
+    pub report: SyncReport,
+    pub error: Box<dyn Error>,
+}
+
+impl From<Box<dyn Error>> for SyncError {
+    fn from(error: Box<dyn Error>) -> Self {
+        SyncError {
+            report: SyncReport::default(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Sync local branches, and report what is done, or would be done with `dry_run`.
+///
+/// On error, the report of what was done until then comes with the error.
+pub fn hubsync(git: &Git, opts: &SyncOptions) -> Result<SyncReport, SyncError> {
+    let mut report = SyncReport::default();
+    match sync(git, opts, &mut report) {
+        Ok(()) => Ok(report),
+        Err(error) => Err(SyncError { report, error }),
     }
 }
 
-pub fn hubsync() -> Result<(), Box<dyn Error>> {
-    let repo = Repository::open_from_env()?;
-    let config = repo.config()?;
-    let git = Git::new(repo, config);
-    let mut current_branch = git.current_branch()?;
-    let mut alternate_remote = None;
+/// Sync local branches, recording what is done in the report as it goes.
+fn sync(git: &Git, opts: &SyncOptions, report: &mut SyncReport) -> Result<(), Box<dyn Error>> {
+    let current_branch = git.current_branch()?;
+    if let Some(ref branch) = current_branch {
+        report.current_branch = Some(ostr!(branch.name()?).to_string());
+    }
 
-    println!("current branch: {}", ostr!(current_branch.name()?));
-    let mut default_remote = find_default_remote(&git)?;
-    println!("default remote: {}", ostr!(default_remote.name()));
-    git.fetch(&mut default_remote)?;
+    let mut default_remote = find_default_remote(git)?;
+    let mut remote_report = fetch(git, opts, &mut default_remote)?;
+    // Tags are compared with the remote only right after fetching them.
+    if remote_report.fetched && git.syncs_tags(&default_remote, opts.tags)? {
+        sync_tags(git, opts, &mut default_remote, &mut remote_report, report)?;
+    }
+    let mut remote_names = vec![remote_report.name.clone()];
     let (remote_default_branch, mut odefault_branch) = git.default_branch(&default_remote)?;
+    remote_report.remote_default = Some(ostr!(remote_default_branch.name()?).to_string());
+    let mut alternate_report = None;
     if let Some(ref default_branch) = odefault_branch {
-        println!("remote default: {}", ostr!(default_branch.name()?));
+        remote_report.local_default = Some(ostr!(default_branch.name()?).to_string());
     } else if let Ok((b, mut r)) = git
         .branch_and_remote("main")
         .or_else(|_| git.branch_and_remote("master"))
     {
-        git.fetch(&mut r)?;
-        println!(
-            "remote default: {} (use local {}/{})",
-            ostr!(remote_default_branch.name()?),
-            ostr!(r.name()),
-            ostr!(b.name()?)
-        );
+        let r_report = fetch(git, opts, &mut r)?;
+        remote_report.local_default = Some(ostr!(b.name()?).to_string());
+        remote_report.alternate_remote = Some(r_report.name.clone());
+        remote_names.push(r_report.name.clone());
         odefault_branch = Some(b);
-        alternate_remote = Some(r);
-    } else {
-        println!(
-            "remote default: {} (no local)",
-            ostr!(remote_default_branch.name()?)
-        );
+        alternate_report = Some(r_report);
+    }
+    report.remotes.push(remote_report);
+    report.remotes.extend(alternate_report);
+    let mut targets = vec![SyncTarget {
+        remote_names,
+        remote_default_branch,
+        odefault_branch,
+    }];
+
+    if opts.all_remotes {
+        for mut remote in other_remotes(git, &targets)? {
+            let mut remote_report = fetch(git, opts, &mut remote)?;
+            // Branches of a remote without default branch are skipped.
+            if let Ok((remote_default_branch, odefault_branch)) = git.default_branch(&remote) {
+                remote_report.remote_default =
+                    Some(ostr!(remote_default_branch.name()?).to_string());
+                if let Some(ref default_branch) = odefault_branch {
+                    remote_report.local_default = Some(ostr!(default_branch.name()?).to_string());
+                }
+                targets.push(SyncTarget {
+                    remote_names: vec![remote_report.name.clone()],
+                    remote_default_branch,
+                    odefault_branch,
+                });
+            }
+            report.remotes.push(remote_report);
+        }
     }
 
-    println!();
+    let mut protect = git.protect_patterns()?;
+    protect.extend(opts.protect.iter().cloned());
+    let mut worktree = Worktree::default();
+    let result = sync_branches(
+        git,
+        opts,
+        &mut targets,
+        current_branch,
+        &protect,
+        &mut worktree,
+        report,
+    );
+    if worktree.stashed {
+        report.autostash = true;
+        if worktree.conflicted {
+            report.warnings.push(
+                "your local changes are left in the stash, \
+                 run \"git stash pop\" after resolving the conflicts"
+                    .to_string(),
+            );
+        } else if !opts.dry_run
+            && let Err(e) = git.unstash()
+        {
+            report.warnings.push(format!(
+                "applying autostash failed: {}; your changes are safe in the stash, \
+                 run \"git stash pop\" or \"git stash drop\" at any time",
+                e
+            ));
+        }
+    }
+    if result.is_ok()
+        && !opts.dry_run
+        && let Err(e) = git.expire_backups()
+    {
+        report
+            .warnings
+            .push(format!("expiring deleted branches failed: {}", e));
+    }
+    result
+}
 
+fn sync_branches<'a>(
+    git: &'a Git,
+    opts: &SyncOptions,
+    targets: &mut [SyncTarget<'a>],
+    mut current_branch: Option<Branch<'a>>,
+    protect: &[String],
+    worktree: &mut Worktree,
+    report: &mut SyncReport,
+) -> Result<(), Box<dyn Error>> {
     for mut branch in git.local_branches()? {
-        let remote = match git.remote(&branch) {
-            Ok(remote) => remote,
-            Err(e) => match e.downcast::<git2::Error>() {
-                Ok(ge) => {
-                    if ge.class() == ErrorClass::Config && ge.code() == ErrorCode::NotFound {
-                        continue;
-                    } else {
-                        return Err(ge);
+        let remote = match branch_remote(git, &branch)? {
+            Some(remote) => remote,
+            None => continue,
+        };
+        let target = match targets.iter_mut().find(|t| t.covers(&remote)) {
+            Some(target) => target,
+            None => continue,
+        };
+        let remote_default_branch = &target.remote_default_branch;
+        let odefault_branch = &mut target.odefault_branch;
+        let branch_name = ostr!(branch.name()?).to_string();
+        let action = match find_branch_action(
+            git,
+            &branch,
+            current_branch.as_ref(),
+            remote_default_branch,
+            odefault_branch.as_ref(),
+        )? {
+            BranchAction::Delete | BranchAction::CheckoutAndDelete
+                if is_protected(&branch_name, protect) =>
+            {
+                BranchAction::Protected
+            }
+            BranchAction::Reset(upstream, _) if is_protected(&branch_name, protect) => {
+                let divergence = git.new_range(&branch, &upstream)?.divergence()?;
+                BranchAction::Unpushed(upstream, divergence)
+            }
+            BranchAction::Unpushed(upstream, divergence) if divergence.behind > 0 => {
+                let oid = branch.get().peel_to_commit()?.id();
+                if is_current(&branch, current_branch.as_ref())? {
+                    match integrate_mode(git, opts, &branch_name)? {
+                        // Protected branches are never rewritten.
+                        Some(Integrate::Rebase) if !is_protected(&branch_name, protect) => {
+                            BranchAction::Rebase(upstream, oid)
+                        }
+                        Some(Integrate::Merge) => BranchAction::MergeCommit(upstream, oid),
+                        _ => BranchAction::Unpushed(upstream, divergence),
                     }
+                } else if opts.rebase_others
+                    && !is_protected(&branch_name, protect)
+                    && git.worktree_of(&branch)?.is_none()
+                {
+                    BranchAction::RebaseRef(upstream, oid)
+                } else {
+                    BranchAction::Unpushed(upstream, divergence)
                 }
-                Err(e) => return Err(e),
-            },
+            }
+            action => action,
         };
-        if remote.name() != default_remote.name() {
-            if let Some(ref r) = alternate_remote {
-                if remote.name() != r.name() {
-                    continue;
-                }
-            } else {
-                continue;
+        let mut branch_report =
+            BranchReport::new(&branch_name, &action, branch.get().peel_to_commit()?.id());
+        if let Some(upstream) = action.upstream() {
+            branch_report.upstream = Some(ostr!(upstream.name()?).to_string());
+            branch_report.new = Some(upstream.get().peel_to_commit()?.id());
+        }
+        let dirty = match action {
+            BranchAction::Merge(..)
+            | BranchAction::Rebase(..)
+            | BranchAction::MergeCommit(..)
+            | BranchAction::CheckoutAndDelete => !worktree.prepare(git, opts)?,
+            BranchAction::Reset(..) if is_current(&branch, current_branch.as_ref())? => {
+                !worktree.prepare(git, opts)?
             }
+            // A missing worktree, e.g. on an unmounted drive, cannot be updated.
+            BranchAction::MergeWorktree(_, _, ref path) => {
+                !path.exists() || git.is_worktree_dirty(path)?
+            }
+            _ => false,
+        };
+        if dirty {
+            branch_report.action = ActionKind::Dirty;
+            branch_report.new = Some(branch_report.old);
+            branch_report.warning = Some(match action {
+                BranchAction::Merge(..)
+                | BranchAction::Reset(..)
+                | BranchAction::Rebase(..)
+                | BranchAction::MergeCommit(..) => format!(
+                    "'{}' was not updated, the working tree has local changes (use --autostash)",
+                    branch_name
+                ),
+                BranchAction::MergeWorktree(_, _, ref path) if !path.exists() => format!(
+                    "'{}' was not updated, the working tree at {} is missing",
+                    branch_name,
+                    path.display()
+                ),
+                BranchAction::MergeWorktree(_, _, ref path) => format!(
+                    "'{}' was not updated, the working tree at {} has local changes",
+                    branch_name,
+                    path.display()
+                ),
+                _ => format!(
+                    "'{}' was not deleted, the working tree has local changes (use --autostash)",
+                    branch_name
+                ),
+            });
+            report.branches.push(branch_report);
+            continue;
         }
-        let action = find_branch_action(
-            &git,
-            &branch,
-            &current_branch,
-            &remote_default_branch,
-            odefault_branch.as_ref(),
-        )?;
         match action {
-            BranchAction::UpToDate => { /* no action */ }
-            BranchAction::Merge(upstream, oid) => {
+            BranchAction::UpToDate(upstream) => {
+                branch_report.upstream = Some(ostr!(upstream.name()?).to_string());
+            }
+            BranchAction::MergeWorktree(_, _, ref path) if opts.dry_run => {
+                branch_report.worktree = Some(path.clone());
+            }
+            BranchAction::Merge(..)
+            | BranchAction::UpdateRef(..)
+            | BranchAction::Rebase(..)
+            | BranchAction::MergeCommit(..)
+            | BranchAction::RebaseRef(..)
+            | BranchAction::Reset(..)
+                if opts.dry_run => {}
+            BranchAction::Reset(upstream, _) => {
+                let checkout = is_current(&branch, current_branch.as_ref())?;
+                branch_report.backup = Some(git.reset_branch(&mut branch, &upstream, checkout)?);
+            }
+            BranchAction::Merge(upstream, _) => {
                 git.fastforward(&mut branch, &upstream)?;
-                println!(
-                    "{} {} (was {:.7})",
-                    "Updated branch".green(),
-                    ostr!(branch.name()?).bright_green(),
-                    oid
-                );
             }
-            BranchAction::UpdateRef(upstream, oid) => {
+            BranchAction::MergeWorktree(upstream, _, path) => {
+                git.fastforward_worktree(&path, &mut branch, &upstream)?;
+                branch_report.worktree = Some(path);
+            }
+            BranchAction::UpdateRef(upstream, _) => {
                 let updated = git.update_ref(&mut branch, &upstream)?;
-                if let Some(ref default_branch) = odefault_branch
+                if let Some(default_branch) = odefault_branch
                     && git::is_branch_same(&branch, default_branch)?
                 {
-                    odefault_branch = Some(updated);
+                    *odefault_branch = Some(updated);
                 }
-                println!(
-                    "{} {} (was {:.7})",
-                    "Updated branch".green(),
-                    ostr!(branch.name()?).bright_green(),
-                    oid
-                );
             }
-            BranchAction::Unpushed => {
-                println!(
-                    "{}: '{}' seems to contain unpushed commits",
-                    "warning".bright_yellow(),
-                    ostr!(branch.name()?)
+            BranchAction::Rebase(upstream, _) => match git.rebase(&branch, &upstream)? {
+                Some(oid) => branch_report.new = Some(oid),
+                None => {
+                    let upstream_name = ostr!(upstream.name()?);
+                    branch_report.action = ActionKind::Conflict;
+                    branch_report.warning = Some(format!(
+                        "rebasing '{}' onto '{}' was aborted because of conflicts; \
+                         run \"git rebase {}\" to resolve them, \
+                         or \"git hubsync --no-rebase\" to merge instead",
+                        branch_name, upstream_name, upstream_name
+                    ));
+                }
+            },
+            BranchAction::MergeCommit(upstream, _) => match git.merge(&branch, &upstream)? {
+                Some(oid) => branch_report.new = Some(oid),
+                None => {
+                    worktree.conflicted = true;
+                    branch_report.action = ActionKind::Conflict;
+                    branch_report.new = None;
+                    branch_report.warning = Some(format!(
+                        "merging '{}' into '{}' stopped because of conflicts; \
+                         resolve them and run \"git commit\", \
+                         or run \"git merge --abort\"",
+                        ostr!(upstream.name()?),
+                        branch_name
+                    ));
+                }
+            },
+            BranchAction::RebaseRef(upstream, _) => match git.rebase_ref(&mut branch, &upstream)? {
+                Some(oid) => branch_report.new = Some(oid),
+                None => {
+                    branch_report.action = ActionKind::Conflict;
+                    branch_report.warning = Some(format!(
+                        "'{}' was not rebased onto '{}' because of conflicts",
+                        branch_name,
+                        ostr!(upstream.name()?)
+                    ));
+                }
+            },
+            BranchAction::Unpushed(upstream, divergence) => {
+                let upstream_name = ostr!(upstream.name()?);
+                let counts = format!(
+                    "{} ahead, {} behind {}",
+                    divergence.ahead, divergence.behind, upstream_name
                 );
+                branch_report.warning = Some(if divergence.base.is_none() {
+                    format!(
+                        "'{}' has no common history with {} ({})",
+                        branch_name, upstream_name, counts
+                    )
+                } else if divergence.behind == 0 {
+                    format!(
+                        "'{}' seems to contain unpushed commits ({})",
+                        branch_name, counts
+                    )
+                } else if opts.integrate.is_none()
+                    && is_current(&branch, current_branch.as_ref())?
+                    && let Some(PullRebase::Unsupported(setting)) = git.pull_rebase(&branch_name)?
+                {
+                    format!(
+                        "'{}' has diverged ({}), and {} is not supported, \
+                         rebase or merge it yourself",
+                        branch_name, counts, setting
+                    )
+                } else {
+                    format!("'{}' has diverged ({})", branch_name, counts)
+                });
+                branch_report.upstream = Some(upstream_name.to_string());
+                branch_report.divergence = Some(divergence);
             }
             BranchAction::Unmerged => {
-                println!(
-                    "{}: '{}' was deleted on {}, but appears not merged into '{}'",
-                    "warning".bright_yellow(),
-                    ostr!(branch.name()?),
+                branch_report.warning = Some(format!(
+                    "'{}' was deleted on {}, but appears not merged into '{}'",
+                    branch_name,
                     ostr!(remote.name()),
                     ostr!(remote_default_branch.name()?)
-                );
+                ));
             }
             BranchAction::CheckoutAndDelete => {
-                let tmp = odefault_branch;
-                odefault_branch = None;
-                if let Some(default_branch) = tmp {
-                    git.checkout(&default_branch)?;
-                    current_branch = default_branch;
+                if let Some(default_branch) = odefault_branch.take() {
+                    if !opts.dry_run {
+                        git.checkout(&default_branch)?;
+                    }
+                    branch_report.checkout = Some(ostr!(default_branch.name()?).to_string());
+                    current_branch = Some(default_branch);
+                }
+                if !opts.dry_run {
+                    branch_report.backup = Some(git.delete_branch(&mut branch)?.refname);
                 }
-                action_delete(&mut branch)?;
+                branch_report.new = None;
             }
             BranchAction::Delete => {
-                action_delete(&mut branch)?;
+                if !opts.dry_run {
+                    branch_report.backup = Some(git.delete_branch(&mut branch)?.refname);
+                }
+                branch_report.new = None;
             }
             BranchAction::NoDefault => {
-                println!(
-                    "{}: no default branch, skipping to delete '{}'",
-                    "warning".bright_yellow(),
-                    ostr!(branch.name()?)
-                );
+                branch_report.warning = Some(format!(
+                    "no default branch, skipping to delete '{}'",
+                    branch_name
+                ));
+            }
+            BranchAction::CheckedOut(path) => {
+                branch_report.warning = Some(format!(
+                    "'{}' was deleted on {}, but is checked out at {}",
+                    branch_name,
+                    ostr!(remote.name()),
+                    path.display()
+                ));
+                branch_report.worktree = Some(path);
+            }
+            BranchAction::DefaultCheckedOut(path) => {
+                let default_branch = ostr!(odefault_branch.as_ref());
+                branch_report.warning = Some(format!(
+                    "'{}' was deleted on {}, but '{}' to switch to is checked out at {}",
+                    branch_name,
+                    ostr!(remote.name()),
+                    ostr!(default_branch.name()?),
+                    path.display()
+                ));
+                branch_report.worktree = Some(path);
+            }
+            BranchAction::Protected => {
+                branch_report.warning = Some(format!(
+                    "'{}' was deleted on {}, but is protected",
+                    branch_name,
+                    ostr!(remote.name())
+                ));
             }
         }
+        report.branches.push(branch_report);
     }
     Ok(())
 }
 
-fn action_delete(branch: &mut Branch) -> Result<(), Box<dyn Error>> {
-    branch.delete()?;
-    println!(
-        "{} {} (was {:.7})",
-        "Deleted branch".magenta(),
-        ostr!(branch.name()?).bright_magenta(),
-        branch.get().peel_to_commit()?.id()
-    );
-    Ok(())
+/// Remote of the branch, or None if the branch has no remote configured.
+fn branch_remote<'a>(
+    git: &'a Git,
+    branch: &Branch,
+) -> Result<Option<git2::Remote<'a>>, Box<dyn Error>> {
+    match git.remote(branch) {
+        Ok(remote) => Ok(Some(remote)),
+        Err(e) => match e.downcast::<git2::Error>() {
+            Ok(ge) => {
+                if ge.class() == ErrorClass::Config && ge.code() == ErrorCode::NotFound {
+                    Ok(None)
+                } else {
+                    Err(ge)
+                }
+            }
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// Remotes of local branches which are not covered by the targets, in order of appearance.
+fn other_remotes<'a>(
+    git: &'a Git,
+    targets: &[SyncTarget],
+) -> Result<Vec<git2::Remote<'a>>, Box<dyn Error>> {
+    let mut remotes: Vec<git2::Remote> = vec![];
+    for branch in git.local_branches()? {
+        if let Some(remote) = branch_remote(git, &branch)?
+            && !targets.iter().any(|t| t.covers(&remote))
+            && !remotes.iter().any(|r| r.name() == remote.name())
+        {
+            remotes.push(remote);
+        }
+    }
+    Ok(remotes)
 }
 
 fn find_branch_action<'a>(
     git: &'a Git,
     branch: &Branch<'a>,
-    current_branch: &Branch,
+    current_branch: Option<&Branch>,
     remote_default_branch: &Branch,
     odefault_branch: Option<&Branch<'a>>,
 ) -> Result<BranchAction<'a>, Box<dyn Error>> {
@@ -189,15 +822,25 @@ fn find_branch_action<'a>(
         Ok(upstream) => {
             let range = git.new_range(branch, &upstream)?;
             if range.is_identical() {
-                Ok(BranchAction::UpToDate)
+                Ok(BranchAction::UpToDate(upstream))
             } else if range.is_ancestor()? {
-                if git::is_branch_same(branch, current_branch)? {
+                if is_current(branch, current_branch)? {
                     Ok(BranchAction::Merge(upstream, range.beg_oid()))
+                } else if let Some(path) = git.worktree_of(branch)? {
+                    Ok(BranchAction::MergeWorktree(
+                        upstream,
+                        range.beg_oid(),
+                        path.to_path_buf(),
+                    ))
                 } else {
                     Ok(BranchAction::UpdateRef(upstream, range.beg_oid()))
                 }
+            } else if git.was_upstream(&upstream, range.beg_oid())?
+                && git.worktree_of(branch)?.is_none()
+            {
+                Ok(BranchAction::Reset(upstream, range.beg_oid()))
             } else {
-                Ok(BranchAction::Unpushed)
+                Ok(BranchAction::Unpushed(upstream, range.divergence()?))
             }
         }
         Err(e) => {
@@ -205,13 +848,19 @@ fn find_branch_action<'a>(
                 || /* pushremote */ e.class() == ErrorClass::Config && e.code() == ErrorCode::NotFound
             {
                 let range = git.new_range(branch, remote_default_branch)?;
-                if range.is_ancestor()? {
-                    if git::is_branch_same(branch, current_branch)? {
-                        if odefault_branch.is_some() {
-                            Ok(BranchAction::CheckoutAndDelete)
-                        } else {
-                            Ok(BranchAction::NoDefault)
+                if range.is_merged()? {
+                    if is_current(branch, current_branch)? {
+                        match odefault_branch {
+                            Some(default_branch) => match git.worktree_of(default_branch)? {
+                                Some(path) => {
+                                    Ok(BranchAction::DefaultCheckedOut(path.to_path_buf()))
+                                }
+                                None => Ok(BranchAction::CheckoutAndDelete),
+                            },
+                            None => Ok(BranchAction::NoDefault),
                         }
+                    } else if let Some(path) = git.worktree_of(branch)? {
+                        Ok(BranchAction::CheckedOut(path.to_path_buf()))
                     } else {
                         Ok(BranchAction::Delete)
                     }
@@ -225,27 +874,86 @@ fn find_branch_action<'a>(
     }
 }
 
+/// How to integrate the diverged current branch, from the option or configuration.
+fn integrate_mode(
+    git: &Git,
+    opts: &SyncOptions,
+    branch_name: &str,
+) -> Result<Option<Integrate>, Box<dyn Error>> {
+    if let Some(integrate) = opts.integrate {
+        return Ok(Some(integrate));
+    }
+    Ok(match git.pull_rebase(branch_name)? {
+        Some(PullRebase::Rebase) => Some(Integrate::Rebase),
+        Some(PullRebase::Merge) => Some(Integrate::Merge),
+        Some(PullRebase::Unsupported(_)) | None => None,
+    })
+}
+
+fn is_current(branch: &Branch, current_branch: Option<&Branch>) -> Result<bool, Box<dyn Error>> {
+    match current_branch {
+        Some(current_branch) => git::is_branch_same(branch, current_branch),
+        None => Ok(false),
+    }
+}
+
+fn is_protected(branch_name: &str, protect: &[String]) -> bool {
+    protect
+        .iter()
+        .any(|pattern| glob_match(pattern.as_bytes(), branch_name.as_bytes()))
+}
+
+/// Match the name against the glob pattern, where `*` and `?` do not match `/`
+/// and `**` matches any string.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => {
+            if let Some(rest) = rest.strip_prefix(b"*") {
+                (0..=name.len()).any(|i| glob_match(rest, &name[i..]))
+            } else {
+                (0..=name.len())
+                    .take_while(|&i| i == 0 || name[i - 1] != b'/')
+                    .any(|i| glob_match(rest, &name[i..]))
+            }
+        }
+        Some((b'?', rest)) => {
+            name.first().is_some_and(|&c| c != b'/') && glob_match(rest, &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
 fn find_default_remote(git: &Git) -> Result<git2::Remote<'_>, Box<dyn Error>> {
     if let Some(remote) = git.only_one_remote()? {
         Ok(remote)
-    } else {
-        let branch = git.current_branch()?;
+    } else if let Some(branch) = git.current_branch()? {
         git.remote(&branch)
+    } else {
+        git.configured_remote()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 mod test {
     use std::env;
     use std::error::Error;
     use std::fs::create_dir;
+    use std::io::{BufRead, Read, Write};
     use std::path::PathBuf;
     use std::process::Command;
     use std::sync::Once;
 
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
     use git2::{self, BranchType, Repository};
 
-    use super::{find_branch_action, find_default_remote};
+    use super::{
+        ActionKind, Integrate, SyncOptions, SyncReport, find_branch_action, find_default_remote,
+        glob_match, hubsync,
+    };
+    use crate::err::AuthRequiredError;
     use crate::git::Git;
 
     static START: Once = Once::new();
@@ -302,7 +1010,7 @@ mod test {
         let action = find_branch_action(
             &git,
             &branch,
-            &current_branch,
+            Some(&current_branch),
             &remote_default_branch,
             default_branch.as_ref(),
         )?;
@@ -463,4 +1171,1419 @@ mod test {
         let action_str = test_find_branch_action("test", "test", Some("master")).unwrap();
         assert_eq!(&action_str, "checkout-and-delete");
     }
+
+    static START4: Once = Once::new();
+
+    fn setup4_once() {
+        START4.call_once(|| {
+            setup4().unwrap();
+        });
+    }
+
+    fn git(args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let status = Command::new("git").args(args).status()?;
+        if !status.success() {
+            return Err(format!("git {} failed", args.join(" ")).into());
+        }
+        Ok(())
+    }
+
+    fn commit(file: &str, content: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(file, content)?;
+        git(&["add", file])?;
+        git(&["commit", "-q", "-m", file])
+    }
+
+    fn setup4() -> Result<(), Box<dyn Error>> {
+        setup_local("ght4")
+    }
+
+    /// Create repository `name` with remote `<name>-origin.git`, both with one commit on
+    /// master.
+    fn setup_minimal(name: &str) -> Result<(), Box<dyn Error>> {
+        let mut tmp_dir = env::temp_dir();
+        tmp_dir.push("git-hubsync-test");
+        if !tmp_dir.is_dir() {
+            create_dir(&tmp_dir)?;
+        }
+        env::set_current_dir(&tmp_dir)?;
+        let origin = format!("{}-origin.git", name);
+        Command::new("rm").args(["-rf", name, &origin]).status()?;
+        git(&["init", "-q", "--bare", "-b", "master", &origin])?;
+        git(&["init", "-q", "-b", "master", name])?;
+        tmp_dir.push(name);
+        env::set_current_dir(&tmp_dir)?;
+        git(&["config", "user.name", "git-hubsync"])?;
+        git(&["config", "user.email", "git-hubsync@example.com"])?;
+        git(&["remote", "add", "origin", &format!("../{}", origin)])?;
+        commit("README", "readme")?;
+        git(&["push", "-q", "-u", "origin", "master"])
+    }
+
+    /// Create repository `name` with remote `<name>-origin.git`, whose master is behind
+    /// origin/master, and local branches merged on origin in several ways.
+    fn setup_local(name: &str) -> Result<(), Box<dyn Error>> {
+        setup_minimal(name)?;
+
+        git(&["switch", "-q", "-c", "merged"])?;
+        commit("merged", "merged")?;
+        git(&["push", "-q", "-u", "origin", "merged"])?;
+        git(&["switch", "-q", "master"])?;
+        git(&["merge", "-q", "--no-ff", "-m", "merge", "merged"])?;
+        git(&["push", "-q", "origin", "master", ":merged"])?;
+
+        git(&["switch", "-q", "-c", "rebased"])?;
+        commit("rebased1", "rebased1")?;
+        commit("rebased2", "rebased2")?;
+        git(&["push", "-q", "-u", "origin", "rebased"])?;
+
+        git(&["switch", "-q", "-c", "squashed", "master"])?;
+        commit("squashed1", "squashed1")?;
+        commit("squashed2", "squashed2")?;
+        git(&["push", "-q", "-u", "origin", "squashed"])?;
+        git(&["switch", "-q", "master"])?;
+        git(&["merge", "-q", "--squash", "squashed"])?;
+        git(&["commit", "-q", "-m", "squashed"])?;
+        git(&["push", "-q", "origin", "master", ":squashed"])?;
+
+        git(&["cherry-pick", "master..rebased"])?;
+        git(&["push", "-q", "origin", "master", ":rebased"])?;
+
+        git(&["branch", "unmerged", "squashed~"])?;
+        git(&["push", "-q", "-u", "origin", "unmerged"])?;
+        git(&["push", "-q", "origin", ":unmerged"])?;
+
+        git(&["reset", "-q", "--hard", "master~3"])?;
+        Ok(())
+    }
+
+    fn test_hubsync(opts: &SyncOptions) -> Result<SyncReport, Box<dyn Error>> {
+        let repo = Repository::open_from_env()?;
+        let config = repo.config()?;
+        let git = Git::new(repo, config);
+        Ok(hubsync(&git, opts)?)
+    }
+
+    #[test]
+    fn test4_find_branch_action_squash_merged() {
+        setup4_once();
+        let action_str = test_find_branch_action("squashed", "master", None).unwrap();
+        assert_eq!(&action_str, "delete");
+    }
+
+    #[test]
+    fn test4_find_branch_action_rebase_merged() {
+        setup4_once();
+        let action_str = test_find_branch_action("rebased", "master", None).unwrap();
+        assert_eq!(&action_str, "delete");
+    }
+
+    #[test]
+    fn test4_find_branch_action_partially_squash_merged() {
+        setup4_once();
+        let action_str = test_find_branch_action("unmerged", "master", None).unwrap();
+        assert_eq!(&action_str, "unmerged");
+    }
+
+    #[test]
+    fn test4_hubsync_dry_run() {
+        setup4_once();
+        git(&["switch", "-q", "merged"]).unwrap();
+        let repo = Repository::open_from_env().unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+
+        let opts = SyncOptions {
+            dry_run: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), master);
+        assert!(repo.find_branch("merged", BranchType::Local).is_ok());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("merged"));
+
+        assert_eq!(report.current_branch.as_deref(), Some("merged"));
+        assert_eq!(
+            report.remotes[0].remote_default.as_deref(),
+            Some("origin/master")
+        );
+        let merged = report.branches.iter().find(|b| b.name == "merged").unwrap();
+        assert_eq!(merged.action, ActionKind::CheckoutAndDelete);
+        assert_eq!(merged.checkout.as_deref(), Some("master"));
+        assert_eq!(merged.new, None);
+    }
+
+    static START5: Once = Once::new();
+
+    fn setup5_once() {
+        START5.call_once(|| {
+            setup_local("ght5").unwrap();
+        });
+    }
+
+    #[test]
+    fn test5_hubsync_dirty() {
+        setup5_once();
+        std::fs::write("README", "modified").unwrap();
+        let repo = Repository::open_from_env().unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), master);
+        assert_eq!(std::fs::read_to_string("README").unwrap(), "modified");
+        let branch = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(branch.action, ActionKind::Dirty);
+        assert!(!report.autostash);
+    }
+
+    static START6: Once = Once::new();
+
+    fn setup6_once() {
+        START6.call_once(|| {
+            setup_local("ght6").unwrap();
+        });
+    }
+
+    #[test]
+    fn test6_hubsync_autostash() {
+        setup6_once();
+        std::fs::write("README", "modified").unwrap();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+
+        let opts = SyncOptions {
+            autostash: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        assert_eq!(
+            repo.refname_to_id("refs/heads/master").unwrap(),
+            origin_master
+        );
+        assert_eq!(std::fs::read_to_string("README").unwrap(), "modified");
+        assert!(report.autostash);
+        assert!(report.warnings.is_empty());
+        assert!(repo.find_reference("refs/stash").is_err());
+    }
+
+    static START7: Once = Once::new();
+
+    fn setup7_once() {
+        START7.call_once(|| {
+            setup7().unwrap();
+        });
+    }
+
+    fn setup7() -> Result<(), Box<dyn Error>> {
+        setup_local("ght7")?;
+        Command::new("rm")
+            .args(["-rf", "../ght7-merged", "../ght7-behind"])
+            .status()?;
+        git(&["branch", "--track", "behind", "origin/master"])?;
+        git(&["branch", "-f", "behind", "origin/master~"])?;
+        git(&["worktree", "add", "-q", "../ght7-merged", "merged"])?;
+        git(&["worktree", "add", "-q", "../ght7-behind", "behind"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test7_hubsync_worktree() {
+        setup7_once();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let merged = report.branches.iter().find(|b| b.name == "merged").unwrap();
+        assert_eq!(merged.action, ActionKind::CheckedOut);
+        assert!(repo.find_branch("merged", BranchType::Local).is_ok());
+
+        let behind = report.branches.iter().find(|b| b.name == "behind").unwrap();
+        assert_eq!(behind.action, ActionKind::MergeWorktree);
+        assert_eq!(
+            repo.refname_to_id("refs/heads/behind").unwrap(),
+            origin_master
+        );
+        assert!(PathBuf::from("../ght7-behind/rebased2").is_file());
+        let behind_repo = Repository::open("../ght7-behind").unwrap();
+        let mut opts = git2::StatusOptions::new();
+        opts.include_untracked(true);
+        assert!(behind_repo.statuses(Some(&mut opts)).unwrap().is_empty());
+    }
+
+    static START8: Once = Once::new();
+
+    fn setup8_once() {
+        START8.call_once(|| {
+            setup_local("ght8").unwrap();
+            git(&["switch", "-q", "--detach", "master"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test8_hubsync_detached() {
+        setup8_once();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        assert_eq!(report.current_branch, None);
+        assert!(repo.head_detached().unwrap());
+        let master = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(master.action, ActionKind::UpdateRef);
+        assert_eq!(
+            repo.refname_to_id("refs/heads/master").unwrap(),
+            origin_master
+        );
+        assert!(repo.find_branch("merged", BranchType::Local).is_err());
+    }
+
+    static START9: Once = Once::new();
+
+    fn setup9_once() {
+        START9.call_once(|| {
+            setup_local("ght9").unwrap();
+            git(&["update-ref", "refs/hubsync/trash/2000-01-01/old", "HEAD"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test9_hubsync_restore() {
+        setup9_once();
+        let repo = Repository::open_from_env().unwrap();
+        let merged = repo.refname_to_id("refs/heads/merged").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let branch = report.branches.iter().find(|b| b.name == "merged").unwrap();
+        assert_eq!(branch.action, ActionKind::Delete);
+        let backup = branch.backup.as_ref().unwrap();
+        assert!(backup.starts_with("refs/hubsync/trash/"));
+        assert!(backup.ends_with("/merged"));
+        assert_eq!(repo.refname_to_id(backup).unwrap(), merged);
+        assert!(
+            repo.find_reference("refs/hubsync/trash/2000-01-01/old")
+                .is_err()
+        );
+
+        let git = Git::new(Repository::open_from_env().unwrap(), repo.config().unwrap());
+        git.restore_branch("merged").unwrap();
+        let branch = repo.find_branch("merged", BranchType::Local).unwrap();
+        assert_eq!(branch.get().target(), Some(merged));
+        let config = repo.config().unwrap().snapshot().unwrap();
+        assert_eq!(
+            config.get_str("branch.merged.merge").unwrap(),
+            "refs/heads/merged"
+        );
+        assert!(repo.find_reference(backup).is_err());
+        assert!(git.restore_branch("merged").is_err());
+    }
+
+    #[test]
+    fn test9_restore_deleted_twice() {
+        setup9_once();
+        let repo = Repository::open_from_env().unwrap();
+        let config = repo.config().unwrap();
+        let hubsync_git = Git::new(repo, config);
+        let delete = |start| {
+            git(&["branch", "twice", start]).unwrap();
+            let mut branches = hubsync_git.local_branches().unwrap();
+            let branch = branches
+                .iter_mut()
+                .find(|b| b.name().unwrap() == Some("twice"))
+                .unwrap();
+            hubsync_git.delete_branch(branch).unwrap()
+        };
+        let first = delete("master~");
+        let second = delete("master");
+
+        assert_ne!(first.refname, second.refname);
+        assert!(second.refname.ends_with(".2/twice"));
+        let repo = Repository::open_from_env().unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+        assert_eq!(hubsync_git.restore_branch("twice").unwrap().oid, master);
+        git(&["branch", "-D", "-q", "twice"]).unwrap();
+        assert_eq!(hubsync_git.restore_branch("twice").unwrap().oid, first.oid);
+        assert_ne!(first.oid, master);
+    }
+
+    static START10: Once = Once::new();
+
+    fn setup10_once() {
+        START10.call_once(|| {
+            setup_local("ght10").unwrap();
+            git(&["switch", "-q", "merged"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test10_hubsync_undo() {
+        setup10_once();
+        let repo = Repository::open_from_env().unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+        let merged = repo.refname_to_id("refs/heads/merged").unwrap();
+        let sync_git = Git::new(Repository::open_from_env().unwrap(), repo.config().unwrap());
+
+        test_hubsync(&SyncOptions::default()).unwrap();
+        assert_eq!(repo.head().unwrap().shorthand(), Some("master"));
+        assert!(repo.find_branch("rebased", BranchType::Local).is_err());
+
+        let entries = sync_git.undo().unwrap();
+        assert!(!entries.is_empty());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("merged"));
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), master);
+        assert_eq!(repo.refname_to_id("refs/heads/merged").unwrap(), merged);
+        assert!(repo.find_branch("rebased", BranchType::Local).is_ok());
+        let config = repo.config().unwrap().snapshot().unwrap();
+        assert_eq!(
+            config.get_str("branch.rebased.merge").unwrap(),
+            "refs/heads/rebased"
+        );
+        assert!(sync_git.backups().unwrap().is_empty());
+        assert!(sync_git.undo().is_err());
+
+        test_hubsync(&SyncOptions::default()).unwrap();
+        git(&["update-ref", "refs/heads/master", "HEAD~"]).unwrap();
+        assert!(sync_git.undo().is_err());
+        assert!(repo.find_branch("rebased", BranchType::Local).is_err());
+    }
+
+    #[test]
+    fn test11_glob_match() {
+        assert!(glob_match(b"develop", b"develop"));
+        assert!(!glob_match(b"develop", b"develop2"));
+        assert!(glob_match(b"release/*", b"release/1.0"));
+        assert!(!glob_match(b"release/*", b"release/1.0/fix"));
+        assert!(glob_match(b"release/**", b"release/1.0/fix"));
+        assert!(glob_match(b"gh-page?", b"gh-pages"));
+        assert!(!glob_match(b"*", b"a/b"));
+    }
+
+    static START11: Once = Once::new();
+
+    fn setup11_once() {
+        START11.call_once(|| {
+            setup_local("ght11").unwrap();
+            git(&["config", "--add", "hubsync.protect", "reb*"]).unwrap();
+            git(&["switch", "-q", "merged"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test11_hubsync_protect() {
+        setup11_once();
+        let repo = Repository::open_from_env().unwrap();
+
+        let opts = SyncOptions {
+            protect: vec!["merged".to_string()],
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        for name in ["merged", "rebased"] {
+            let branch = report.branches.iter().find(|b| b.name == name).unwrap();
+            assert_eq!(branch.action, ActionKind::Protected);
+            assert!(branch.warning.is_some());
+            assert!(repo.find_branch(name, BranchType::Local).is_ok());
+        }
+        let squashed = report
+            .branches
+            .iter()
+            .find(|b| b.name == "squashed")
+            .unwrap();
+        assert_eq!(squashed.action, ActionKind::Delete);
+        assert_eq!(repo.head().unwrap().shorthand(), Some("merged"));
+        let master = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(master.action, ActionKind::UpdateRef);
+    }
+
+    static START12: Once = Once::new();
+
+    fn setup12_once() {
+        START12.call_once(|| {
+            setup_local("ght12").unwrap();
+            commit("local", "local").unwrap();
+        });
+    }
+
+    #[test]
+    fn test12_hubsync_rebase() {
+        setup12_once();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+
+        let opts = SyncOptions {
+            integrate: Some(Integrate::Rebase),
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        let branch = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(branch.action, ActionKind::Rebase);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(Some(head.id()), branch.new);
+        assert_eq!(head.parent_id(0).unwrap(), origin_master);
+        assert!(PathBuf::from("local").is_file());
+    }
+
+    static START13: Once = Once::new();
+
+    fn setup13_once() {
+        START13.call_once(|| {
+            setup_local("ght13").unwrap();
+            commit("local", "local").unwrap();
+            git(&["config", "pull.rebase", "false"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test13_hubsync_merge_commit() {
+        setup13_once();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let branch = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(branch.action, ActionKind::MergeCommit);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.parent_ids().collect::<Vec<_>>(),
+            [master, origin_master]
+        );
+        assert_eq!(repo.state(), git2::RepositoryState::Clean);
+    }
+
+    #[test]
+    fn test13_hubsync_rebase_merges_unsupported() {
+        setup_local("ght13-merges").unwrap();
+        commit("local", "local").unwrap();
+        git(&["config", "branch.master.rebase", "merges"]).unwrap();
+        let repo = Repository::open_from_env().unwrap();
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let branch = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(branch.action, ActionKind::Unpushed);
+        assert!(
+            branch
+                .warning
+                .as_ref()
+                .unwrap()
+                .contains("branch.master.rebase=merges is not supported")
+        );
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), master);
+    }
+
+    static START14: Once = Once::new();
+
+    fn setup14_once() {
+        START14.call_once(|| {
+            setup14().unwrap();
+        });
+    }
+
+    /// Add branches `side` and `conflict` diverged from their upstreams, the latter
+    /// with conflicting changes.
+    fn setup14() -> Result<(), Box<dyn Error>> {
+        setup_local("ght14")?;
+        for (name, file) in [("side", "side-local"), ("conflict", "conflict")] {
+            git(&["switch", "-q", "-c", name, "origin/master"])?;
+            commit("conflict", "base")?;
+            git(&["push", "-q", "-u", "origin", name])?;
+            commit("conflict", "remote")?;
+            git(&["push", "-q", "origin", name])?;
+            git(&["reset", "-q", "--hard", "HEAD~"])?;
+            commit(file, "local")?;
+        }
+        git(&["switch", "-q", "master"])
+    }
+
+    #[test]
+    fn test14_hubsync_rebase_others() {
+        setup14_once();
+        let repo = Repository::open_from_env().unwrap();
+        let origin_side = repo.refname_to_id("refs/remotes/origin/side").unwrap();
+        let conflict = repo.refname_to_id("refs/heads/conflict").unwrap();
+
+        let opts = SyncOptions {
+            rebase_others: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        let side = report.branches.iter().find(|b| b.name == "side").unwrap();
+        assert_eq!(side.action, ActionKind::RebaseRef);
+        let tip = repo.find_branch("side", BranchType::Local).unwrap();
+        let tip = tip.get().peel_to_commit().unwrap();
+        assert_eq!(Some(tip.id()), side.new);
+        assert_eq!(tip.parent_id(0).unwrap(), origin_side);
+        assert!(tip.tree().unwrap().get_name("side-local").is_some());
+
+        let branch = report
+            .branches
+            .iter()
+            .find(|b| b.name == "conflict")
+            .unwrap();
+        assert_eq!(branch.action, ActionKind::Conflict);
+        assert!(branch.warning.is_some());
+        assert_eq!(repo.refname_to_id("refs/heads/conflict").unwrap(), conflict);
+        assert_eq!(repo.head().unwrap().shorthand(), Some("master"));
+        assert!(!PathBuf::from("side-local").exists());
+    }
+
+    static START15: Once = Once::new();
+
+    fn setup15_once() {
+        START15.call_once(|| {
+            setup15().unwrap();
+        });
+    }
+
+    fn setup15() -> Result<(), Box<dyn Error>> {
+        setup_local("ght15")?;
+        git(&["switch", "-q", "-c", "ahead", "origin/master"])?;
+        git(&["push", "-q", "-u", "origin", "ahead"])?;
+        commit("ahead", "ahead")?;
+        git(&["switch", "-q", "master"])?;
+        commit("local", "local")
+    }
+
+    #[test]
+    fn test15_hubsync_divergence() {
+        setup15_once();
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let ahead = report.branches.iter().find(|b| b.name == "ahead").unwrap();
+        assert_eq!(ahead.action, ActionKind::Unpushed);
+        let divergence = ahead.divergence.unwrap();
+        assert_eq!((divergence.ahead, divergence.behind), (1, 0));
+        assert_eq!(
+            ahead.warning.as_deref(),
+            Some("'ahead' seems to contain unpushed commits (1 ahead, 0 behind origin/ahead)")
+        );
+
+        let master = report.branches.iter().find(|b| b.name == "master").unwrap();
+        assert_eq!(master.action, ActionKind::Unpushed);
+        let divergence = master.divergence.unwrap();
+        assert_eq!((divergence.ahead, divergence.behind), (1, 3));
+        assert!(divergence.base.is_some());
+        assert_eq!(
+            master.warning.as_deref(),
+            Some("'master' has diverged (1 ahead, 3 behind origin/master)")
+        );
+    }
+
+    static START16: Once = Once::new();
+
+    fn setup16_once() {
+        START16.call_once(|| {
+            setup16().unwrap();
+        });
+    }
+
+    /// Add branches `forced` and `mine` whose upstreams are force-pushed on origin,
+    /// the latter with a local commit.
+    fn setup16() -> Result<(), Box<dyn Error>> {
+        setup_local("ght16")?;
+        git(&["switch", "-q", "-c", "rewritten", "origin/master"])?;
+        commit("rewritten", "rewritten")?;
+        git(&["push", "-q", "origin", "rewritten"])?;
+        for name in ["forced", "mine"] {
+            git(&["switch", "-q", "-c", name, "origin/master"])?;
+            commit(name, name)?;
+            git(&["push", "-q", "-u", "origin", name])?;
+            git(&[
+                "--git-dir",
+                "../ght16-origin.git",
+                "update-ref",
+                &format!("refs/heads/{}", name),
+                "refs/heads/rewritten",
+            ])?;
+        }
+        commit("mine2", "mine2")?;
+        git(&["switch", "-q", "master"])
+    }
+
+    #[test]
+    fn test16_hubsync_force_pushed() {
+        setup16_once();
+        let repo = Repository::open_from_env().unwrap();
+        let forced = repo.refname_to_id("refs/heads/forced").unwrap();
+        let mine = repo.refname_to_id("refs/heads/mine").unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+        let rewritten = repo.refname_to_id("refs/remotes/origin/forced").unwrap();
+
+        let branch = report.branches.iter().find(|b| b.name == "forced").unwrap();
+        assert_eq!(branch.action, ActionKind::Reset);
+        assert_eq!(repo.refname_to_id("refs/heads/forced").unwrap(), rewritten);
+        let saved = branch.backup.as_ref().unwrap();
+        assert!(saved.starts_with("refs/hubsync/reset/"));
+        assert_eq!(repo.refname_to_id(saved).unwrap(), forced);
+
+        let branch = report.branches.iter().find(|b| b.name == "mine").unwrap();
+        assert_eq!(branch.action, ActionKind::Unpushed);
+        assert_eq!(repo.refname_to_id("refs/heads/mine").unwrap(), mine);
+    }
+
+    static START17: Once = Once::new();
+
+    fn setup17_once() {
+        START17.call_once(|| {
+            setup17().unwrap();
+        });
+    }
+
+    /// Add tags `kept`, `gone` deleted on origin and `moved` moved on origin.
+    fn setup17() -> Result<(), Box<dyn Error>> {
+        setup_local("ght17")?;
+        git(&["tag", "-a", "-m", "kept", "kept", "origin/master"])?;
+        git(&["tag", "gone", "origin/master"])?;
+        git(&["tag", "moved", "origin/master"])?;
+        git(&["push", "-q", "origin", "kept", "gone", "moved"])?;
+        git(&["push", "-q", "origin", ":refs/tags/gone"])?;
+        git(&[
+            "--git-dir",
+            "../ght17-origin.git",
+            "update-ref",
+            "refs/tags/moved",
+            "refs/heads/master~",
+        ])
+    }
+
+    #[test]
+    fn test17_hubsync_tags() {
+        setup17_once();
+        let repo = Repository::open_from_env().unwrap();
+        let moved = repo.refname_to_id("refs/tags/moved").unwrap();
+
+        let opts = SyncOptions {
+            tags: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        assert!(repo.find_reference("refs/tags/gone").is_err());
+        assert!(repo.find_reference("refs/tags/kept").is_ok());
+        assert_eq!(repo.refname_to_id("refs/tags/moved").unwrap(), moved);
+        assert!(
+            report.remotes[0]
+                .updates
+                .iter()
+                .any(|u| u.refname == "refs/tags/gone" && u.is_deleted())
+        );
+        assert!(report.warnings.iter().any(|w| w.starts_with("tag 'moved'")));
+
+        let opts = SyncOptions {
+            tags: true,
+            clobber_tags: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        let origin_master = repo.refname_to_id("refs/remotes/origin/master").unwrap();
+        let moved = repo
+            .find_commit(origin_master)
+            .unwrap()
+            .parent_id(0)
+            .unwrap();
+        assert_eq!(repo.refname_to_id("refs/tags/moved").unwrap(), moved);
+        assert!(report.warnings.is_empty());
+    }
+
+    /// Push a new branch `fresh` to origin from another clone, and serve origin over
+    /// smart HTTP so that the remote sends progress over the sideband.
+    fn setup18() -> Result<(), Box<dyn Error>> {
+        setup_minimal("ght18")?;
+        Command::new("rm")
+            .args(["-rf", "../ght18-other"])
+            .status()?;
+        git(&["clone", "-q", "../ght18-origin.git", "../ght18-other"])?;
+        git(&[
+            "-C",
+            "../ght18-other",
+            "-c",
+            "user.name=git-hubsync",
+            "-c",
+            "user.email=git-hubsync@example.com",
+            "commit",
+            "-q",
+            "--allow-empty",
+            "-m",
+            "fresh",
+        ])?;
+        git(&["-C", "../ght18-other", "push", "-q", "origin", "HEAD:fresh"])?;
+        let port = start_http_backend(std::fs::canonicalize("..")?, None);
+        let url = format!("http://127.0.0.1:{}/ght18-origin.git", port);
+        git(&["remote", "set-url", "origin", &url])
+    }
+
+    #[test]
+    fn test18_hubsync_remote_messages() {
+        setup18().unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let remote = &report.remotes[0];
+        assert!(
+            remote
+                .updates
+                .iter()
+                .any(|u| u.refname == "refs/remotes/origin/fresh" && u.is_new())
+        );
+        // Progress of the remote is rewritten by '\r', only the final state is kept.
+        assert!(
+            remote
+                .messages
+                .iter()
+                .any(|m| m == "Counting objects: 100% (1/1), done.")
+        );
+    }
+
+    /// Create CA `ght20-tls/ca.pem`, also in `ght20-tls/capath`, and the certificate of
+    /// localhost signed by it.
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    fn setup20() -> Result<(), Box<dyn Error>> {
+        setup_minimal("ght20")?;
+        Command::new("rm").args(["-rf", "../ght20-tls"]).status()?;
+        create_dir("../ght20-tls")?;
+        std::fs::write("../ght20-tls/san.cnf", "subjectAltName=DNS:localhost\n")?;
+        let openssl = |args: &str| -> Result<(), Box<dyn Error>> {
+            let status = Command::new("openssl")
+                .args(args.split_whitespace())
+                .current_dir("../ght20-tls")
+                .stderr(std::process::Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(format!("openssl {} failed", args).into());
+            }
+            Ok(())
+        };
+        openssl(
+            "req -x509 -newkey rsa:2048 -nodes -days 1 -keyout ca.key -out ca.pem \
+             -subj /CN=git-hubsync-test-ca",
+        )?;
+        openssl(
+            "req -newkey rsa:2048 -nodes -keyout server.key -out server.csr \
+             -subj /CN=localhost",
+        )?;
+        openssl(
+            "x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 1 \
+             -extfile san.cnf -out server.pem",
+        )?;
+        create_dir("../ght20-tls/capath")?;
+        std::fs::copy("../ght20-tls/ca.pem", "../ght20-tls/capath/ca.pem")?;
+        openssl("rehash capath")
+    }
+
+    /// HTTPS stand-in answering any request with a status page, killed on drop.
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    struct TlsServer(std::process::Child);
+
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    impl TlsServer {
+        fn start(port: u16) -> Result<Self, Box<dyn Error>> {
+            let child = Command::new("openssl")
+                .args(["s_server", "-quiet", "-www", "-cert", "server.pem"])
+                .args(["-key", "server.key", "-accept", &port.to_string()])
+                .current_dir("../ght20-tls")
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .spawn()?;
+            let server = TlsServer(child);
+            for _ in 0..50 {
+                if std::net::TcpStream::connect(("localhost", port)).is_ok() {
+                    return Ok(server);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err("openssl s_server did not start".into())
+        }
+    }
+
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    impl Drop for TlsServer {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // CA locations are only supported by the OpenSSL backend of libgit2.
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    #[test]
+    fn test20_fetch_tls() {
+        setup20().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let _server = TlsServer::start(port).unwrap();
+        let url = format!("https://localhost:{}/repo.git", port);
+        git(&["remote", "add", "tls", &url]).unwrap();
+        let fetch_error = || {
+            let repo = Repository::open_from_env().unwrap();
+            let config = repo.config().unwrap();
+            let git = Git::new(repo, config);
+            let remote_repo = Repository::open_from_env().unwrap();
+            let mut remote = remote_repo.find_remote("tls").unwrap();
+            git.fetch(&mut remote).unwrap_err().to_string()
+        };
+
+        // Untrusted CA.
+        assert!(fetch_error().contains("http.sslCAInfo"));
+
+        // The stand-in is not a Git server, but TLS handshake succeeds.
+        git(&["config", &format!("http.{}.sslVerify", url), "false"]).unwrap();
+        assert!(!fetch_error().contains("http.sslCAInfo"));
+
+        git(&["config", "--unset", &format!("http.{}.sslVerify", url)]).unwrap();
+        // sslCAPath, as GIT_SSL_CAINFO may be set to the system bundle.
+        let ca_path = std::fs::canonicalize("../ght20-tls/capath").unwrap();
+        let key = format!("http.https://localhost:{}.sslCAPath", port);
+        git(&["config", &key, ca_path.to_str().unwrap()]).unwrap();
+        assert!(!fetch_error().contains("http.sslCAInfo"));
+    }
+
+    /// Start an HTTP server answering any request with 401, and return its port.
+    fn start_unauthorized_server() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(
+                    b"HTTP/1.1 401 Unauthorized\r\n\
+                      WWW-Authenticate: Basic realm=\"git-hubsync\"\r\n\
+                      Content-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test22_fetch_no_prompt() {
+        setup_minimal("ght22").unwrap();
+        let port = start_unauthorized_server();
+        let url = format!("http://127.0.0.1:{}/repo.git", port);
+        git(&["remote", "add", "private", &url]).unwrap();
+        let fetch = || {
+            let repo = Repository::open_from_env().unwrap();
+            let config = repo.config().unwrap();
+            let mut git = Git::new(repo, config);
+            git.set_prompt(false);
+            let remote_repo = Repository::open_from_env().unwrap();
+            let mut remote = remote_repo.find_remote("private").unwrap();
+            git.fetch(&mut remote).unwrap_err()
+        };
+
+        let e = fetch();
+        assert!(e.is::<AuthRequiredError>(), "{}", e);
+
+        // askpass is still used, and the server rejects its credentials.
+        std::fs::write(
+            "askpass.sh",
+            "#!/bin/sh\necho \"$1\" >> askpass.log\necho hubsync\n",
+        )
+        .unwrap();
+        git(&["config", "core.askPass", "./askpass.sh"]).unwrap();
+        Command::new("chmod")
+            .args(["+x", "askpass.sh"])
+            .status()
+            .unwrap();
+        let e = fetch();
+        assert!(!e.is::<AuthRequiredError>(), "{}", e);
+        let log = std::fs::read_to_string("askpass.log").unwrap();
+        assert!(log.starts_with("Username: \nPassword for 'hubsync': \n"));
+    }
+
+    struct Request {
+        line: String,
+        /// Headers with lowercase names.
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> &str {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map_or("", |(_, v)| v.as_str())
+        }
+    }
+
+    fn read_request(stream: &mut std::net::TcpStream) -> Option<Request> {
+        let mut reader = std::io::BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let request_line = line.trim_end().to_string();
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+        let mut body = vec![];
+        if header("transfer-encoding").is_some_and(|v| v == "chunked") {
+            loop {
+                line.clear();
+                reader.read_line(&mut line).ok()?;
+                let size = usize::from_str_radix(line.trim_end(), 16).ok()?;
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).ok()?;
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(length) = header("content-length") {
+            body.resize(length.parse().ok()?, 0);
+            reader.read_exact(&mut body).ok()?;
+        }
+        Some(Request {
+            line: request_line,
+            headers,
+            body,
+        })
+    }
+
+    /// Start a smart HTTP server of the repositories in the directory with `git http-backend`,
+    /// requiring the basic authentication if credentials are given, and return its port.
+    fn start_http_backend(root: PathBuf, credentials: Option<String>) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let Some(request) = read_request(&mut stream) else {
+                    continue;
+                };
+                let authorized = credentials.as_ref().is_none_or(|credentials| {
+                    request
+                        .header("authorization")
+                        .strip_prefix("Basic ")
+                        .and_then(|encoded| STANDARD.decode(encoded).ok())
+                        .is_some_and(|decoded| decoded == credentials.as_bytes())
+                });
+                if !authorized {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 401 Unauthorized\r\n\
+                          WWW-Authenticate: Basic realm=\"git-hubsync\"\r\n\
+                          Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    continue;
+                }
+                let mut fields = request.line.split(' ');
+                let method = fields.next().unwrap_or("");
+                let target = fields.next().unwrap_or("");
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let mut child = Command::new("git")
+                    .arg("http-backend")
+                    .env("GIT_PROJECT_ROOT", &root)
+                    .env("GIT_HTTP_EXPORT_ALL", "1")
+                    .env("REQUEST_METHOD", method)
+                    .env("PATH_INFO", path)
+                    .env("QUERY_STRING", query)
+                    .env("CONTENT_TYPE", request.header("content-type"))
+                    .env("CONTENT_LENGTH", request.body.len().to_string())
+                    .stdin(std::process::Stdio::piped())
+                    .stdout(std::process::Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(&request.body)
+                    .unwrap();
+                let output = child.wait_with_output().unwrap();
+                let split = output
+                    .stdout
+                    .windows(4)
+                    .position(|w| w == b"\r\n\r\n")
+                    .unwrap();
+                let cgi_headers = String::from_utf8_lossy(&output.stdout[..split]).to_string();
+                let content = &output.stdout[split + 4..];
+                let mut status = "200 OK".to_string();
+                let mut response = vec![];
+                for line in cgi_headers.lines() {
+                    match line.strip_prefix("Status: ") {
+                        Some(s) => status = s.to_string(),
+                        None => response.push(line),
+                    }
+                }
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\n{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        response.join("\r\n"),
+                        content.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(content);
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test23_fetch_token() {
+        setup_minimal("ght23").unwrap();
+        let root = std::fs::canonicalize("..").unwrap();
+        let port = start_http_backend(root, Some("oauth2:t0ken".to_string()));
+        let url = format!("http://127.0.0.1:{}/ght23-origin.git", port);
+        git(&["remote", "set-url", "origin", &url]).unwrap();
+        git(&["push", "-q", "../ght23-origin.git", "master:token"]).unwrap();
+        // SAFETY: tests run on one thread, as they change the current directory.
+        unsafe { env::set_var("HUBSYNC_TEST_TOKEN", "t0ken") };
+        git(&[
+            "config",
+            &format!("hubsync.http://127.0.0.1:{}/.tokenEnv", port),
+            "HUBSYNC_TEST_TOKEN",
+        ])
+        .unwrap();
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        assert!(
+            report.remotes[0]
+                .updates
+                .iter()
+                .any(|u| u.refname == "refs/remotes/origin/token" && u.is_new())
+        );
+    }
+
+    static START24: Once = Once::new();
+
+    fn setup24_once() {
+        START24.call_once(|| {
+            setup_local("ght24").unwrap();
+        });
+    }
+
+    #[test]
+    fn test24_hubsync_without_fetch() {
+        setup24_once();
+        let repo = Repository::open_from_env().unwrap();
+        let is_fetched = |report: &SyncReport| report.remotes[0].fetched;
+
+        let opts = SyncOptions {
+            fetch: false,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        assert!(!is_fetched(&report));
+        assert!(repo.find_branch("merged", BranchType::Local).is_err());
+        assert!(!repo.path().join("FETCH_HEAD").exists());
+
+        git(&["config", "hubsync.fetchMaxAge", "3600"]).unwrap();
+        assert!(is_fetched(&test_hubsync(&SyncOptions::default()).unwrap()));
+
+        // Fetched within the window by FETCH_HEAD.
+        git(&[
+            "--git-dir",
+            "../ght24-origin.git",
+            "branch",
+            "newer",
+            "master",
+        ])
+        .unwrap();
+        assert!(!is_fetched(&test_hubsync(&SyncOptions::default()).unwrap()));
+        assert!(repo.find_reference("refs/remotes/origin/newer").is_err());
+
+        git(&["config", "hubsync.fetchMaxAge", "0"]).unwrap();
+        assert!(is_fetched(&test_hubsync(&SyncOptions::default()).unwrap()));
+        assert!(repo.find_reference("refs/remotes/origin/newer").is_ok());
+
+        // Fetched within the window by the reflog of origin/newer.
+        std::fs::remove_file(repo.path().join("FETCH_HEAD")).unwrap();
+        git(&["config", "hubsync.fetchMaxAge", "3600"]).unwrap();
+        git(&[
+            "--git-dir",
+            "../ght24-origin.git",
+            "branch",
+            "newest",
+            "master",
+        ])
+        .unwrap();
+        assert!(!is_fetched(&test_hubsync(&SyncOptions::default()).unwrap()));
+        assert!(repo.find_reference("refs/remotes/origin/newest").is_err());
+    }
+
+    static START25: Once = Once::new();
+
+    /// Check out merged branch `merged`, with master, ahead of it, checked out in
+    /// `ght25-master`.
+    fn setup25_once() {
+        START25.call_once(|| {
+            setup_local("ght25").unwrap();
+            Command::new("rm")
+                .args(["-rf", "../ght25-master"])
+                .status()
+                .unwrap();
+            git(&["reset", "-q", "--hard", "origin/master"]).unwrap();
+            git(&["switch", "-q", "merged"]).unwrap();
+            git(&["worktree", "add", "-q", "../ght25-master", "master"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test25_hubsync_default_checked_out() {
+        setup25_once();
+        let repo = Repository::open_from_env().unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let merged = report.branches.iter().find(|b| b.name == "merged").unwrap();
+        assert_eq!(merged.action, ActionKind::DefaultCheckedOut);
+        assert!(merged.warning.as_ref().unwrap().contains("'master'"));
+        assert!(repo.find_branch("merged", BranchType::Local).is_ok());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("merged"));
+    }
+
+    #[test]
+    fn test25_checkout_checked_out() {
+        setup25_once();
+        let repo = Repository::open_from_env().unwrap();
+        let config = repo.config().unwrap();
+        let git = Git::new(repo, config);
+        let branches = git.local_branches().unwrap();
+        let master = branches
+            .iter()
+            .find(|b| b.name().unwrap() == Some("master"))
+            .unwrap();
+
+        assert!(git.checkout(master).is_err());
+
+        let repo = Repository::open_from_env().unwrap();
+        assert_eq!(repo.head().unwrap().shorthand(), Some("merged"));
+        assert!(!PathBuf::from("rebased1").exists());
+    }
+
+    static START26: Once = Once::new();
+
+    /// Add tag `pushed` on origin, and local-only tags `mylocal` (annotated) and `light`.
+    fn setup26_once() {
+        START26.call_once(|| {
+            setup_local("ght26").unwrap();
+            git(&["tag", "pushed", "origin/master"]).unwrap();
+            git(&["push", "-q", "origin", "pushed"]).unwrap();
+            git(&["tag", "-a", "-m", "mylocal", "mylocal", "master"]).unwrap();
+            git(&["tag", "light", "master"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test26_hubsync_tags_undo() {
+        setup26_once();
+        let repo = Repository::open_from_env().unwrap();
+        let mylocal = repo.refname_to_id("refs/tags/mylocal").unwrap();
+        let light = repo.refname_to_id("refs/tags/light").unwrap();
+
+        let opts = SyncOptions {
+            tags: true,
+            ..SyncOptions::default()
+        };
+        test_hubsync(&opts).unwrap();
+
+        assert!(repo.find_reference("refs/tags/mylocal").is_err());
+        assert!(repo.find_reference("refs/tags/light").is_err());
+        assert!(repo.find_reference("refs/tags/pushed").is_ok());
+        let backups = repo
+            .references_glob("refs/hubsync/tags/*")
+            .unwrap()
+            .map(|r| r.unwrap())
+            .map(|r| (r.name().unwrap().to_string(), r.target().unwrap()))
+            .collect::<Vec<_>>();
+        assert!(
+            backups
+                .iter()
+                .any(|(name, oid)| name.ends_with("/mylocal") && *oid == mylocal)
+        );
+
+        let git = Git::new(Repository::open_from_env().unwrap(), repo.config().unwrap());
+        git.undo().unwrap();
+
+        assert_eq!(repo.refname_to_id("refs/tags/mylocal").unwrap(), mylocal);
+        assert_eq!(repo.refname_to_id("refs/tags/light").unwrap(), light);
+        assert_eq!(
+            repo.references_glob("refs/hubsync/tags/*").unwrap().count(),
+            0
+        );
+    }
+
+    static START27: Once = Once::new();
+
+    /// Add remote `upstream` whose default branch `main` is tracked by local `main`, with
+    /// branch `feature` merged and deleted, and branch `stale` deleted behind our back.
+    fn setup27_once() {
+        START27.call_once(|| {
+            setup_local("ght27").unwrap();
+            let upstream = "../ght27-upstream.git";
+            Command::new("rm").args(["-rf", upstream]).status().unwrap();
+            git(&["init", "-q", "--bare", "-b", "main", upstream]).unwrap();
+            git(&["remote", "add", "upstream", upstream]).unwrap();
+            git(&["push", "-q", "upstream", "master:main", "master:stale"]).unwrap();
+            git(&["branch", "-q", "--track", "main", "upstream/main"]).unwrap();
+            git(&["switch", "-q", "-c", "feature", "main"]).unwrap();
+            commit("feature", "feature").unwrap();
+            git(&["push", "-q", "-u", "upstream", "feature"]).unwrap();
+            git(&["push", "-q", "upstream", "feature:main", ":feature"]).unwrap();
+            git(&["switch", "-q", "master"]).unwrap();
+            git(&["--git-dir", upstream, "branch", "-q", "-D", "stale"]).unwrap();
+        });
+    }
+
+    #[test]
+    fn test27_hubsync_all_remotes() {
+        setup27_once();
+        let repo = Repository::open_from_env().unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        assert!(report.remotes.iter().all(|r| r.name != "upstream"));
+        assert!(report.branches.iter().all(|b| b.name != "feature"));
+        assert!(repo.find_branch("feature", BranchType::Local).is_ok());
+        assert!(repo.find_reference("refs/remotes/upstream/stale").is_ok());
+
+        let opts = SyncOptions {
+            all_remotes: true,
+            ..SyncOptions::default()
+        };
+        let report = test_hubsync(&opts).unwrap();
+
+        let upstream = report
+            .remotes
+            .iter()
+            .find(|r| r.name == "upstream")
+            .unwrap();
+        assert!(upstream.fetched);
+        assert_eq!(upstream.remote_default.as_deref(), Some("upstream/main"));
+        assert_eq!(upstream.local_default.as_deref(), Some("main"));
+        assert!(
+            upstream
+                .updates
+                .iter()
+                .any(|u| u.refname == "refs/remotes/upstream/stale" && u.is_deleted())
+        );
+        assert!(repo.find_reference("refs/remotes/upstream/stale").is_err());
+        let feature = report
+            .branches
+            .iter()
+            .find(|b| b.name == "feature")
+            .unwrap();
+        assert_eq!(feature.action, ActionKind::Delete);
+        assert!(repo.find_branch("feature", BranchType::Local).is_err());
+        assert!(repo.find_branch("main", BranchType::Local).is_ok());
+        assert_eq!(repo.head().unwrap().shorthand(), Some("master"));
+    }
+
+    #[test]
+    fn test28_hubsync_missing_worktree() {
+        setup_local("ght28").unwrap();
+        Command::new("rm")
+            .args(["-rf", "../ght28-locked", "../ght28-pruned"])
+            .status()
+            .unwrap();
+        git(&["worktree", "add", "-q", "../ght28-locked", "merged"]).unwrap();
+        git(&["worktree", "lock", "../ght28-locked"]).unwrap();
+        git(&["worktree", "add", "-q", "../ght28-pruned", "rebased"]).unwrap();
+        Command::new("rm")
+            .args(["-rf", "../ght28-locked", "../ght28-pruned"])
+            .status()
+            .unwrap();
+        let repo = Repository::open_from_env().unwrap();
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        let action = |name| {
+            report
+                .branches
+                .iter()
+                .find(|b| b.name == name)
+                .unwrap()
+                .action
+        };
+        assert_eq!(action("merged"), ActionKind::CheckedOut);
+        assert!(repo.find_branch("merged", BranchType::Local).is_ok());
+        assert_eq!(action("rebased"), ActionKind::Delete);
+        assert!(repo.find_branch("rebased", BranchType::Local).is_err());
+    }
+
+    /// Create `ght29-unborn` with no commits, whose `master` is behind origin/master, and
+    /// `gone` merged and deleted on origin.
+    fn setup29() -> Result<(), Box<dyn Error>> {
+        setup_minimal("ght29")?;
+        commit("second", "second")?;
+        git(&["push", "-q", "origin", "master", "master:gone"])?;
+        Command::new("rm")
+            .args(["-rf", "../ght29-unborn"])
+            .status()?;
+        git(&["init", "-q", "-b", "main", "../ght29-unborn"])?;
+        env::set_current_dir("../ght29-unborn")?;
+        git(&["remote", "add", "origin", "../ght29-origin.git"])?;
+        git(&["fetch", "-q", "origin"])?;
+        git(&["branch", "-q", "master", "origin/master~"])?;
+        git(&["branch", "-q", "-u", "origin/master", "master"])?;
+        git(&["branch", "-q", "gone", "origin/gone"])?;
+        git(&["branch", "-q", "-u", "origin/gone", "gone"])?;
+        git(&["push", "-q", "origin", ":gone"])
+    }
+
+    #[test]
+    fn test29_hubsync_unborn_head() {
+        setup29().unwrap();
+        let repo = Repository::open_from_env().unwrap();
+        let unborn = |repo: &Repository| {
+            repo.head()
+                .is_err_and(|e| e.code() == git2::ErrorCode::UnbornBranch)
+        };
+        assert!(unborn(&repo));
+
+        let report = test_hubsync(&SyncOptions::default()).unwrap();
+
+        assert_eq!(report.current_branch, None);
+        let action = |name| {
+            report
+                .branches
+                .iter()
+                .find(|b| b.name == name)
+                .unwrap()
+                .action
+        };
+        assert_eq!(action("master"), ActionKind::UpdateRef);
+        assert_eq!(
+            repo.refname_to_id("refs/heads/master").unwrap(),
+            repo.refname_to_id("refs/remotes/origin/master").unwrap()
+        );
+        assert_eq!(action("gone"), ActionKind::Delete);
+        assert!(repo.find_branch("gone", BranchType::Local).is_err());
+        assert!(unborn(&repo));
+    }
 }
@@ -0,0 +1,256 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use git2::Oid;
+
+use crate::err::GitError;
+
+/// A change made by a sync, as recorded in the journal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalEntry {
+    /// Ref updated without touching any worktree.
+    Update { refname: String, old: Oid, new: Oid },
+    /// Current branch fast-forwarded, rebased or merged along with the worktree.
+    FastForward { refname: String, old: Oid, new: Oid },
+    /// Branch fast-forwarded along with the other worktree at the path.
+    Worktree {
+        refname: String,
+        old: Oid,
+        new: Oid,
+        path: PathBuf,
+    },
+    /// HEAD switched from the old target to the new one.
+    Head { old: String, new: String },
+    /// Branch or tag deleted at the oid, with its backup ref and, for a branch, its
+    /// `branch.<name>` config.
+    Delete {
+        refname: String,
+        oid: Oid,
+        backup: String,
+        config: Vec<(String, String)>,
+    },
+}
+
+impl JournalEntry {
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            JournalEntry::Update { refname, old, new } => {
+                writeln!(w, "update {} {} {}", refname, old, new)
+            }
+            JournalEntry::FastForward { refname, old, new } => {
+                writeln!(w, "fastforward {} {} {}", refname, old, new)
+            }
+            JournalEntry::Worktree {
+                refname,
+                old,
+                new,
+                path,
+            } => writeln!(
+                w,
+                "worktree {} {} {} {}",
+                refname,
+                old,
+                new,
+                escape(&path.to_string_lossy())
+            ),
+            JournalEntry::Head { old, new } => writeln!(w, "head {} {}", old, new),
+            JournalEntry::Delete {
+                refname,
+                oid,
+                backup,
+                config,
+            } => {
+                writeln!(w, "delete {} {} {}", refname, oid, backup)?;
+                for (key, value) in config {
+                    writeln!(w, "config {} {}", key, escape(value))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Journal of the last sync that changed anything, at `<git-common-dir>/hubsync/journal`.
+///
+/// The file is truncated by the first change of a run and appended to on each change,
+/// so that a run which failed halfway can be undone too.
+pub struct Journal {
+    path: PathBuf,
+    file: RefCell<Option<File>>,
+}
+
+impl Journal {
+    pub fn new(commondir: &Path) -> Self {
+        Journal {
+            path: commondir.join("hubsync").join("journal"),
+            file: RefCell::new(None),
+        }
+    }
+
+    pub fn record(&self, entry: &JournalEntry) -> Result<(), Box<dyn Error>> {
+        let mut file = self.file.borrow_mut();
+        if file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&self.path)?,
+            );
+        }
+        if let Some(file) = file.as_mut() {
+            entry.write_to(file)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Entries of the last sync in order, or None if there is nothing to undo.
+    pub fn read(&self) -> Result<Option<Vec<JournalEntry>>, Box<dyn Error>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = vec![];
+        for line in content.lines() {
+            let entry = parse_line(line, entries.last_mut())
+                .ok_or_else(|| GitError::new(format!("invalid journal line: {}", line)))?;
+            entries.extend(entry);
+        }
+        Ok(Some(entries))
+    }
+
+    pub fn remove(&self) -> Result<(), Box<dyn Error>> {
+        Ok(fs::remove_file(&self.path)?)
+    }
+}
+
+/// Escape backslashes and line breaks of a free-form field, so that an entry stays on
+/// its line.
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Parse the line as an entry, or as config of the previous delete entry.
+fn parse_line(line: &str, prev: Option<&mut JournalEntry>) -> Option<Option<JournalEntry>> {
+    let (kind, rest) = line.split_once(' ')?;
+    if kind == "config" {
+        let (key, value) = rest.split_once(' ')?;
+        if let Some(JournalEntry::Delete { config, .. }) = prev {
+            config.push((key.to_string(), unescape(value)));
+            return Some(None);
+        }
+        return None;
+    }
+    if kind == "head" {
+        let (old, new) = rest.split_once(' ')?;
+        return Some(Some(JournalEntry::Head {
+            old: old.to_string(),
+            new: new.to_string(),
+        }));
+    }
+    let mut fields = rest.splitn(4, ' ');
+    let refname = fields.next()?.to_string();
+    let old = Oid::from_str(fields.next()?).ok()?;
+    let third = fields.next()?;
+    let entry = match kind {
+        "update" => JournalEntry::Update {
+            refname,
+            old,
+            new: Oid::from_str(third).ok()?,
+        },
+        "fastforward" => JournalEntry::FastForward {
+            refname,
+            old,
+            new: Oid::from_str(third).ok()?,
+        },
+        "worktree" => JournalEntry::Worktree {
+            refname,
+            old,
+            new: Oid::from_str(third).ok()?,
+            path: PathBuf::from(unescape(fields.next()?)),
+        },
+        "delete" => JournalEntry::Delete {
+            refname,
+            oid: old,
+            backup: third.to_string(),
+            config: vec![],
+        },
+        _ => return None,
+    };
+    Some(Some(entry))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use git2::Oid;
+
+    use super::{Journal, JournalEntry};
+    use crate::http::test::temp_dir;
+
+    #[test]
+    fn test_round_trip() {
+        let journal = Journal::new(&temp_dir());
+        let oid = |c: char| Oid::from_str(&c.to_string().repeat(40)).unwrap();
+        let entries = vec![
+            JournalEntry::Head {
+                old: "refs/heads/topic".to_string(),
+                new: "refs/heads/main".to_string(),
+            },
+            JournalEntry::Worktree {
+                refname: "refs/heads/main".to_string(),
+                old: oid('1'),
+                new: oid('2'),
+                path: PathBuf::from("/work/my tree\nwith\nbreaks"),
+            },
+            JournalEntry::Delete {
+                refname: "refs/heads/topic".to_string(),
+                oid: oid('3'),
+                backup: "refs/hubsync/trash/2024-01-31/topic".to_string(),
+                config: vec![
+                    ("branch.topic.remote".to_string(), "origin".to_string()),
+                    (
+                        "branch.topic.description".to_string(),
+                        "first line\nsecond\r\nC:\\path\\n".to_string(),
+                    ),
+                ],
+            },
+        ];
+
+        for entry in &entries {
+            journal.record(entry).unwrap();
+        }
+
+        assert_eq!(journal.read().unwrap(), Some(entries));
+    }
+}
@@ -0,0 +1,445 @@
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use git2::Config;
+use git2::cert::CertHostkey;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use url::Url;
+
+use crate::err::GitError;
+
+/// What to do with a host key not in known_hosts, by `hubsync.strictHostKeyChecking`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Reject unknown keys, the default.
+    Strict,
+    /// Add unknown keys to the user known_hosts file.
+    AcceptNew,
+    /// Add unknown keys, and accept changed keys too.
+    Off,
+}
+
+impl HostKeyPolicy {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let Ok(value) = config.get_string("hubsync.strictHostKeyChecking") else {
+            return Ok(HostKeyPolicy::Strict);
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            value => match Config::parse_bool(value) {
+                Ok(true) => Ok(HostKeyPolicy::Strict),
+                Ok(false) => Ok(HostKeyPolicy::Off),
+                Err(_) => Err(GitError::new(format!(
+                    "invalid hubsync.strictHostKeyChecking '{}', expected yes, accept-new or no",
+                    value
+                ))
+                .into()),
+            },
+        }
+    }
+}
+
+/// Result of looking up a host key in known_hosts.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    Known,
+    /// No key of the type is known for the host.
+    Unknown,
+    /// Another key of the type is known for the host, at the line of the file.
+    Changed {
+        file: PathBuf,
+        line: usize,
+    },
+    /// The key is marked as `@revoked` at the line of the file.
+    Revoked {
+        file: PathBuf,
+        line: usize,
+    },
+}
+
+struct Entry {
+    file: PathBuf,
+    line: usize,
+    revoked: bool,
+    hosts: String,
+    key_type: String,
+    key: Vec<u8>,
+}
+
+/// Host keys of the user and global known_hosts files, like OpenSSH.
+///
+/// The files are `hubsync.userKnownHostsFile` (`~/.ssh/known_hosts` and
+/// `~/.ssh/known_hosts2` by default) and `hubsync.globalKnownHostsFile`
+/// (`/etc/ssh/ssh_known_hosts` and `/etc/ssh/ssh_known_hosts2` by default).
+pub struct KnownHosts {
+    /// None if not configured and the home directory is unknown.
+    user_file: Option<PathBuf>,
+    entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    pub fn load(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let (user_file, mut files) = match config.get_path("hubsync.userKnownHostsFile") {
+            Ok(path) => (Some(path.clone()), vec![path]),
+            // The home directory is $HOME or that of the user, %USERPROFILE% on Windows.
+            Err(_) => match env::home_dir() {
+                Some(home) => {
+                    let ssh_dir = home.join(".ssh");
+                    (
+                        Some(ssh_dir.join("known_hosts")),
+                        vec![ssh_dir.join("known_hosts"), ssh_dir.join("known_hosts2")],
+                    )
+                }
+                None => (None, vec![]),
+            },
+        };
+        match config.get_path("hubsync.globalKnownHostsFile") {
+            Ok(path) => files.push(path),
+            Err(_) => files.extend([
+                PathBuf::from("/etc/ssh/ssh_known_hosts"),
+                PathBuf::from("/etc/ssh/ssh_known_hosts2"),
+            ]),
+        }
+        let mut entries = vec![];
+        for file in files {
+            let content = match fs::read_to_string(&file) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for (i, line) in content.lines().enumerate() {
+                if let Some(entry) = parse_line(&file, i + 1, line) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(KnownHosts { user_file, entries })
+    }
+
+    pub fn check(&self, host: &str, port: u16, key_type: &str, key: &[u8]) -> HostKeyStatus {
+        let name = host_name(host, port);
+        let mut status = HostKeyStatus::Unknown;
+        for entry in self.entries.iter().filter(|e| match_hosts(&e.hosts, &name)) {
+            if entry.revoked {
+                if entry.key == key {
+                    return HostKeyStatus::Revoked {
+                        file: entry.file.clone(),
+                        line: entry.line,
+                    };
+                }
+            } else if entry.key == key {
+                status = HostKeyStatus::Known;
+            } else if entry.key_type == key_type && status == HostKeyStatus::Unknown {
+                status = HostKeyStatus::Changed {
+                    file: entry.file.clone(),
+                    line: entry.line,
+                };
+            }
+        }
+        status
+    }
+
+    /// Append the key of the host to the user known_hosts file.
+    pub fn add(
+        &self,
+        host: &str,
+        port: u16,
+        key_type: &str,
+        key: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let user_file = self.user_file()?;
+        if let Some(dir) = user_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(user_file)?;
+        writeln!(
+            file,
+            "{} {} {}",
+            host_name(host, port),
+            key_type,
+            STANDARD.encode(key)
+        )?;
+        Ok(())
+    }
+
+    fn user_file(&self) -> Result<&Path, Box<dyn Error>> {
+        match &self.user_file {
+            Some(user_file) => Ok(user_file),
+            None => Err(GitError::new(
+                "unable to find the home directory for known_hosts, \
+                 set hubsync.userKnownHostsFile"
+                    .to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Verify the SSH host key of the host against known_hosts, according to the policy.
+pub fn verify_host_key(
+    config: &Config,
+    host: &str,
+    port: u16,
+    hostkey: &CertHostkey,
+) -> Result<(), Box<dyn Error>> {
+    let (Some(key), Some(key_type)) = (hostkey.hostkey(), hostkey.hostkey_type()) else {
+        return Err(GitError::new(format!("unable to get the host key of {}", host)).into());
+    };
+    let fingerprint = hostkey
+        .hash_sha256()
+        .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+        .unwrap_or_default();
+    let policy = HostKeyPolicy::from_config(config)?;
+    let known_hosts = KnownHosts::load(config)?;
+    match known_hosts.check(host, port, key_type.name(), key) {
+        HostKeyStatus::Known => Ok(()),
+        HostKeyStatus::Unknown if policy != HostKeyPolicy::Strict => {
+            known_hosts.add(host, port, key_type.name(), key)
+        }
+        HostKeyStatus::Unknown => Err(GitError::new(format!(
+            "host key of {} is not known ({} key {}); add it to {}, \
+             or set hubsync.strictHostKeyChecking to accept-new",
+            host_name(host, port),
+            key_type.short_name(),
+            fingerprint,
+            known_hosts.user_file()?.display()
+        ))
+        .into()),
+        HostKeyStatus::Changed { .. } if policy == HostKeyPolicy::Off => Ok(()),
+        HostKeyStatus::Changed { file, line } => Err(GitError::new(format!(
+            "host key of {} has changed ({} key {}), someone may be eavesdropping; \
+             if the change is legitimate, remove the old key at {}:{}",
+            host_name(host, port),
+            key_type.short_name(),
+            fingerprint,
+            file.display(),
+            line
+        ))
+        .into()),
+        HostKeyStatus::Revoked { file, line } => Err(GitError::new(format!(
+            "host key of {} is revoked at {}:{} ({} key {})",
+            host_name(host, port),
+            file.display(),
+            line,
+            key_type.short_name(),
+            fingerprint
+        ))
+        .into()),
+    }
+}
+
+/// Port of the SSH URL, 22 unless given by `ssh://host:port/...`.
+pub fn ssh_port(url: &str) -> u16 {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "ssh" | "git+ssh" | "ssh+git"))
+        .and_then(|url| url.port())
+        .unwrap_or(22)
+}
+
+/// Name of the host in known_hosts, with the port unless it is 22.
+fn host_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Parse `[@marker] <hosts> <key-type> <base64-key> [comment]`. Comments, invalid lines
+/// and `@cert-authority` lines are skipped.
+fn parse_line(file: &Path, line: usize, text: &str) -> Option<Entry> {
+    let mut fields = text.split_whitespace();
+    let mut hosts = fields.next()?;
+    if hosts.starts_with('#') {
+        return None;
+    }
+    let revoked = match hosts {
+        "@revoked" => true,
+        "@cert-authority" => return None,
+        _ => false,
+    };
+    if revoked {
+        hosts = fields.next()?;
+    }
+    let key_type = fields.next()?;
+    let key = STANDARD.decode(fields.next()?).ok()?;
+    Some(Entry {
+        file: file.to_path_buf(),
+        line,
+        revoked,
+        hosts: hosts.to_string(),
+        key_type: key_type.to_string(),
+        key,
+    })
+}
+
+/// Match the host name with a hashed `|1|<salt>|<hash>` entry, or with comma-separated
+/// patterns, where `*` and `?` are wildcards and a matching `!` pattern excludes the host.
+fn match_hosts(hosts: &str, name: &str) -> bool {
+    if let Some(hashed) = hosts.strip_prefix("|1|") {
+        let Some((salt, hash)) = hashed.split_once('|') else {
+            return false;
+        };
+        return match (STANDARD.decode(salt), STANDARD.decode(hash)) {
+            (Ok(salt), Ok(hash)) => hmac_sha1(&salt, name.as_bytes()) == Some(hash),
+            _ => false,
+        };
+    }
+    let name = name.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        if wildcard_match(pattern.to_ascii_lowercase().as_bytes(), name.as_bytes()) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn wildcard_match(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], s) || (!s.is_empty() && wildcard_match(pattern, &s[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &s[1..]),
+        (Some(p), Some(c)) if p == c => wildcard_match(&pattern[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+/// HMAC-SHA1 of the host name keyed with the salt, as hashed by `ssh-keygen -H`.
+fn hmac_sha1(salt: &[u8], name: &[u8]) -> Option<Vec<u8>> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(salt).ok()?;
+    mac.update(name);
+    Some(mac.finalize().into_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use git2::Config;
+
+    use super::{HostKeyStatus, KnownHosts, hmac_sha1, ssh_port};
+    use crate::http::test::{config_with, temp_dir};
+
+    const KEY1: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIA8N0JCMwAJcL1BYYsgIX1KAUVEnW/twF8nKjR08M1g+";
+    const KEY2: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIPLCbptO97Q6XlKdutdVCdFPNSLJ2IcTJ9IKP2ybRD1h";
+
+    /// Key1 of example.com and [example.com]:2222, hashed by `ssh-keygen -H`, key1 of
+    /// *.example.org except git.example.org, and revoked key2.
+    const KNOWN_HOSTS: &str = "\
+|1|eWVPZAiv7kHJeFcabYYnpxkhi8Q=|G7H9OXzpUS8WpaxHfmUO14n0BIU= ssh-ed25519 \
+AAAAC3NzaC1lZDI1NTE5AAAAIA8N0JCMwAJcL1BYYsgIX1KAUVEnW/twF8nKjR08M1g+
+|1|IeaY32O2zzZB4V17CEAAhswjP6o=|DMSoYlAvuPvRe7R+W5NdOvig184= ssh-ed25519 \
+AAAAC3NzaC1lZDI1NTE5AAAAIA8N0JCMwAJcL1BYYsgIX1KAUVEnW/twF8nKjR08M1g+
+# comment
+@cert-authority * ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPLCbptO97Q6XlKdutdVCdFPNSLJ2IcTJ9IKP2ybRD1h
+*.example.org,!git.example.org ssh-ed25519 \
+AAAAC3NzaC1lZDI1NTE5AAAAIA8N0JCMwAJcL1BYYsgIX1KAUVEnW/twF8nKjR08M1g+
+@revoked * ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPLCbptO97Q6XlKdutdVCdFPNSLJ2IcTJ9IKP2ybRD1h
+";
+
+    /// Config with `known_hosts` of the content as the user file.
+    fn setup(content: &str) -> Config {
+        let dir = temp_dir();
+        fs::write(dir.join("known_hosts"), content).unwrap();
+        let path = |file: &str| dir.join(file).to_str().unwrap().to_string();
+        config_with(&[
+            ("hubsync.userKnownHostsFile", &path("known_hosts")),
+            ("hubsync.globalKnownHostsFile", &path("global_known_hosts")),
+        ])
+    }
+
+    fn key(key: &str) -> Vec<u8> {
+        STANDARD.decode(key).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let config = setup(KNOWN_HOSTS);
+        let known_hosts = KnownHosts::load(&config).unwrap();
+        let check = |host, port, key: &[u8]| known_hosts.check(host, port, "ssh-ed25519", key);
+        let key1 = key(KEY1);
+        let mut other = key1.clone();
+        *other.last_mut().unwrap() ^= 1;
+
+        assert_eq!(check("example.com", 22, &key1), HostKeyStatus::Known);
+        assert_eq!(check("example.com", 2222, &key1), HostKeyStatus::Known);
+        assert_eq!(check("example.com", 2200, &key1), HostKeyStatus::Unknown);
+        assert_eq!(
+            check("example.com", 22, &other),
+            HostKeyStatus::Changed {
+                file: config.get_path("hubsync.userKnownHostsFile").unwrap(),
+                line: 1
+            }
+        );
+        assert_eq!(check("ci.example.org", 22, &key1), HostKeyStatus::Known);
+        assert_eq!(check("git.example.org", 22, &key1), HostKeyStatus::Unknown);
+        assert!(matches!(
+            check("example.com", 22, &key(KEY2)),
+            HostKeyStatus::Revoked { line: 6, .. }
+        ));
+    }
+
+    #[test]
+    fn test_add() {
+        let config = setup("");
+        let known_hosts = KnownHosts::load(&config).unwrap();
+        let key1 = key(KEY1);
+
+        known_hosts
+            .add("example.net", 2222, "ssh-ed25519", &key1)
+            .unwrap();
+
+        let path = config.get_path("hubsync.userKnownHostsFile").unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("[example.net]:2222 ssh-ed25519 {}\n", KEY1)
+        );
+        let known_hosts = KnownHosts::load(&config).unwrap();
+        assert_eq!(
+            known_hosts.check("example.net", 2222, "ssh-ed25519", &key1),
+            HostKeyStatus::Known
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202, test case 2.
+        assert_eq!(
+            hmac_sha1(b"Jefe", b"what do ya want for nothing?").unwrap(),
+            [
+                0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74, 0x16, 0xd5, 0xf1, 0x84,
+                0xdf, 0x9c, 0x25, 0x9a, 0x7c, 0x79
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ssh_port() {
+        assert_eq!(ssh_port("ssh://git@example.com:2222/repo.git"), 2222);
+        assert_eq!(ssh_port("ssh://git@example.com/repo.git"), 22);
+        assert_eq!(ssh_port("git@example.com:repo.git"), 22);
+        assert_eq!(ssh_port("https://example.com:8443/repo.git"), 22);
+    }
+}
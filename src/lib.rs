@@ -0,0 +1,22 @@
+//! Sync local branches with their remote branches, like `hub sync`.
+
+mod err;
+#[macro_use]
+mod git;
+mod http;
+mod hubsync;
+mod journal;
+mod known_hosts;
+mod progress;
+mod prompt;
+mod token;
+
+pub use crate::err::{AuthRequiredError, GitError};
+pub use crate::git::{
+    Backup, Divergence, Fetched, Git, PullRebase, RefKind, RefUpdate, TagMismatch, TagSync,
+};
+pub use crate::hubsync::{
+    ActionKind, BranchAction, BranchReport, Integrate, RemoteReport, SyncError, SyncOptions,
+    SyncReport, hubsync,
+};
+pub use crate::journal::JournalEntry;
@@ -1,15 +1,220 @@
+use std::env;
+use std::error::Error;
+use std::io::{self, IsTerminal};
 use std::process::exit;
 
 use colored::Colorize;
+use git2::Repository;
 
-mod err;
-#[macro_use]
-mod git;
-mod hubsync;
+use git_hubsync::{
+    AuthRequiredError, Git, GitError, Integrate, JournalEntry, SyncError, SyncOptions, SyncReport,
+};
+
+mod output;
+
+use crate::output::Format;
+
+const USAGE: &str = "usage: git hubsync [<options>]
+   or: git hubsync restore [<branch>]
+   or: git hubsync undo
+
+    -a, --all-remotes    sync branches of all remotes, not only the default remote
+    -n, --dry-run        show what would be done, without changing anything
+    --no-fetch           do not fetch, use existing remote-tracking branches
+    --autostash          stash local changes before switching or fast-forwarding
+    -r, --rebase         rebase the current branch onto its upstream if diverged
+    --no-rebase          merge the upstream into the current branch if diverged
+    --rebase-others      rebase diverged branches that are not checked out
+    --tags               delete local tags not on the remote, even if never pushed,
+                         and report moved tags
+    --clobber-tags       update local tags moved on the remote, when syncing tags
+    --protect=<pattern>  never delete branches matching the glob pattern
+    --no-prompt          fail instead of prompting for credentials on the terminal
+    --format=<format>    output format, human (default), json or porcelain
+    --porcelain          same as --format=porcelain";
+
+/// Exit code when authentication is needed but prompting is disabled.
+const EXIT_AUTH_REQUIRED: i32 = 3;
+
+enum Command {
+    /// Sync, prompting for credentials on the terminal if the last is true.
+    Sync(SyncOptions, Format, bool),
+    /// Restore the deleted branch, or list deleted branches if None.
+    Restore(Option<String>),
+    /// Revert the last sync.
+    Undo,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Command, GitError> {
+    let mut args = args.peekable();
+    if args.peek().map(|arg| arg.as_str()) == Some("restore") {
+        args.next();
+        let name = args.next();
+        if let Some(arg) = args.next() {
+            return Err(GitError::new(format!(
+                "unexpected argument '{}'\n{}",
+                arg, USAGE
+            )));
+        }
+        return Ok(Command::Restore(name));
+    }
+    if args.peek().map(|arg| arg.as_str()) == Some("undo") {
+        args.next();
+        if let Some(arg) = args.next() {
+            return Err(GitError::new(format!(
+                "unexpected argument '{}'\n{}",
+                arg, USAGE
+            )));
+        }
+        return Ok(Command::Undo);
+    }
+    let mut opts = SyncOptions::default();
+    let mut format = Format::Human;
+    let mut prompt = true;
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--format=") {
+            format = Format::parse(value)
+                .ok_or_else(|| GitError::new(format!("unknown format '{}'", value)))?;
+            continue;
+        }
+        if let Some(value) = arg.strip_prefix("--protect=") {
+            opts.protect.push(value.to_string());
+            continue;
+        }
+        match arg.as_str() {
+            "--porcelain" => format = Format::Porcelain,
+            "-a" | "--all-remotes" => opts.all_remotes = true,
+            "-n" | "--dry-run" => opts.dry_run = true,
+            "--autostash" => opts.autostash = true,
+            "-r" | "--rebase" => opts.integrate = Some(Integrate::Rebase),
+            "--no-rebase" => opts.integrate = Some(Integrate::Merge),
+            "--rebase-others" => opts.rebase_others = true,
+            "--tags" => opts.tags = true,
+            "--clobber-tags" => opts.clobber_tags = true,
+            "--no-fetch" => opts.fetch = false,
+            "--no-prompt" => prompt = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ => {
+                return Err(GitError::new(format!(
+                    "unknown option '{}'\n{}",
+                    arg, USAGE
+                )));
+            }
+        }
+    }
+    Ok(Command::Sync(opts, format, prompt))
+}
+
+fn open() -> Result<Git, Box<dyn Error>> {
+    let repo = Repository::open_from_env()?;
+    let config = repo.config()?;
+    let mut git = Git::new(repo, config);
+    git.set_progress(io::stderr().is_terminal());
+    Ok(git)
+}
+
+fn sync(opts: &SyncOptions, prompt: bool) -> Result<SyncReport, SyncError> {
+    let mut git = open()?;
+    if !prompt {
+        git.set_prompt(false);
+    }
+    git_hubsync::hubsync(&git, opts)
+}
+
+fn restore(name: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let git = open()?;
+    if let Some(name) = name {
+        let backup = git.restore_branch(name)?;
+        println!(
+            "{} {} (was {:.7})",
+            "Restored branch".green(),
+            name.bright_green(),
+            backup.oid
+        );
+    } else {
+        for backup in git.backups()? {
+            println!("{} {:.7} {}", backup.date, backup.oid, backup.name);
+        }
+    }
+    Ok(())
+}
+
+fn undo() -> Result<(), Box<dyn Error>> {
+    let entries = open()?.undo()?;
+    for entry in entries.iter().rev() {
+        match entry {
+            JournalEntry::Update { refname, old, .. }
+            | JournalEntry::FastForward { refname, old, .. }
+            | JournalEntry::Worktree { refname, old, .. } => {
+                println!(
+                    "{} {} to {:.7}",
+                    "Reset branch".green(),
+                    short_name(refname).bright_green(),
+                    old
+                );
+            }
+            JournalEntry::Head { old, .. } => {
+                println!(
+                    "{} {}",
+                    "Switched back to branch".green(),
+                    short_name(old).bright_green()
+                );
+            }
+            JournalEntry::Delete { refname, oid, .. } => {
+                let (restored, name) = match refname.strip_prefix("refs/tags/") {
+                    Some(name) => ("Restored tag", name),
+                    None => ("Restored branch", short_name(refname)),
+                };
+                println!(
+                    "{} {} (at {:.7})",
+                    restored.green(),
+                    name.bright_green(),
+                    oid
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn short_name(refname: &str) -> &str {
+    refname.strip_prefix("refs/heads/").unwrap_or(refname)
+}
+
+fn fatal(e: &(dyn Error + 'static)) -> ! {
+    eprintln!("{}: {}", "fatal".bright_red(), e);
+    if e.is::<AuthRequiredError>() {
+        exit(EXIT_AUTH_REQUIRED);
+    }
+    exit(1);
+}
 
 fn main() {
-    if let Err(e) = hubsync::hubsync() {
-        eprintln!("{}: {}", "fatal".bright_red(), e);
-        exit(1);
+    let (opts, format, prompt) = match parse_args(env::args().skip(1)) {
+        Ok(Command::Sync(opts, format, prompt)) => (opts, format, prompt),
+        Ok(Command::Restore(name)) => {
+            if let Err(e) = restore(name.as_deref()) {
+                fatal(e.as_ref());
+            }
+            return;
+        }
+        Ok(Command::Undo) => {
+            if let Err(e) = undo() {
+                fatal(e.as_ref());
+            }
+            return;
+        }
+        Err(e) => fatal(&e),
+    };
+    let (report, error) = match sync(&opts, prompt) {
+        Ok(report) => (report, None),
+        Err(e) => (e.report, Some(e.error)),
+    };
+    output::print_report(format, &opts, &report, error.as_deref());
+    if let Some(e) = error {
+        fatal(e.as_ref());
     }
 }
@@ -0,0 +1,597 @@
+use std::error::Error;
+use std::io::{self, Write};
+
+use colored::Colorize;
+use git2::Oid;
+use serde_json::{Value, json};
+
+use git_hubsync::{ActionKind, BranchReport, RefUpdate, RemoteReport, SyncOptions, SyncReport};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    Json,
+    Porcelain,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            "porcelain" => Some(Format::Porcelain),
+            _ => None,
+        }
+    }
+}
+
+/// Print the report, with the error if the sync failed.
+/// Human format leaves the error to the caller.
+pub fn print_report(
+    format: Format,
+    opts: &SyncOptions,
+    report: &SyncReport,
+    error: Option<&dyn Error>,
+) {
+    match format {
+        Format::Human => print_human(opts, report),
+        Format::Json => println!("{:#}", to_json(opts, report, error)),
+        Format::Porcelain => print_porcelain(report, error),
+    }
+}
+
+fn print_ref_update(update: &RefUpdate) {
+    if update.is_deleted() {
+        println!(
+            " - [deleted]               {:14} -> {}",
+            update.src, update.dst
+        );
+    } else if update.is_new() {
+        println!(
+            " * {:24}{:14} -> {}",
+            format!("[new {}]", update.kind.as_str()),
+            update.src,
+            update.dst
+        );
+    } else if update.forced {
+        println!(
+            " + {:.10}..{:.10}  {:14} -> {:14} (forced update)",
+            update.old, update.new, update.src, update.dst
+        );
+    } else {
+        println!(
+            "   {:.10}..{:.10}  {:14} -> {:14}",
+            update.old, update.new, update.src, update.dst
+        );
+    }
+}
+
+fn print_remote_default(remote: &RemoteReport) {
+    match (&remote.remote_default, &remote.local_default) {
+        (Some(remote_default), Some(local_default)) => {
+            if let Some(ref alternate) = remote.alternate_remote {
+                println!(
+                    "remote default: {} (use local {}/{})",
+                    remote_default, alternate, local_default
+                );
+            } else {
+                println!("remote default: {}", local_default);
+            }
+        }
+        (Some(remote_default), None) => {
+            println!("remote default: {} (no local)", remote_default);
+        }
+        (None, _) => {
+            println!(
+                "{}: no default branch on {}, skipping its branches",
+                "warning".bright_yellow(),
+                remote.name
+            );
+        }
+    }
+}
+
+fn print_remotes(opts: &SyncOptions, remotes: &[RemoteReport]) {
+    let mut alternate = None;
+    for (i, remote) in remotes.iter().enumerate() {
+        if i == 0 {
+            println!("default remote: {}", remote.name);
+            alternate = remote.alternate_remote.as_ref();
+        } else if Some(&remote.name) != alternate {
+            println!();
+            println!("remote: {}", remote.name);
+        }
+        if opts.fetch && !remote.fetched {
+            println!(" (fetch of {} skipped, fetched recently)", remote.name);
+        }
+        for update in &remote.updates {
+            print_ref_update(update);
+        }
+        if Some(&remote.name) != alternate {
+            print_remote_default(remote);
+        }
+    }
+}
+
+fn print_branch(opts: &SyncOptions, branch: &BranchReport) {
+    if let Some(ref warning) = branch.warning {
+        println!("{}: {}", "warning".bright_yellow(), warning);
+        return;
+    }
+    if let Some(ref checkout) = branch.checkout
+        && opts.dry_run
+    {
+        println!(
+            "{} {}",
+            "Would switch to branch".green(),
+            checkout.bright_green()
+        );
+    }
+    match branch.action {
+        ActionKind::Merge | ActionKind::MergeWorktree | ActionKind::UpdateRef => {
+            if opts.dry_run {
+                println!(
+                    "{} {} ({} {}, at {:.7})",
+                    "Would update branch".green(),
+                    branch.name.bright_green(),
+                    branch.action.as_str(),
+                    branch.upstream.as_deref().unwrap_or(""),
+                    branch.old
+                );
+            } else if let Some(ref path) = branch.worktree {
+                println!(
+                    "{} {} in {} (was {:.7})",
+                    "Updated branch".green(),
+                    branch.name.bright_green(),
+                    path.display(),
+                    branch.old
+                );
+            } else {
+                println!(
+                    "{} {} (was {:.7})",
+                    "Updated branch".green(),
+                    branch.name.bright_green(),
+                    branch.old
+                );
+            }
+        }
+        ActionKind::Reset
+        | ActionKind::Rebase
+        | ActionKind::MergeCommit
+        | ActionKind::RebaseRef => {
+            let upstream = branch.upstream.as_deref().unwrap_or("");
+            if opts.dry_run {
+                println!(
+                    "{} {} ({} {}, at {:.7})",
+                    "Would update branch".green(),
+                    branch.name.bright_green(),
+                    branch.action.as_str(),
+                    upstream,
+                    branch.old
+                );
+            } else if branch.action == ActionKind::Reset {
+                println!(
+                    "{} {} to {} (was {:.7}, saved as {})",
+                    "Reset branch".green(),
+                    branch.name.bright_green(),
+                    upstream,
+                    branch.old,
+                    branch.backup.as_deref().unwrap_or("")
+                );
+            } else if branch.action != ActionKind::MergeCommit {
+                println!(
+                    "{} {} onto {} (was {:.7})",
+                    "Rebased branch".green(),
+                    branch.name.bright_green(),
+                    upstream,
+                    branch.old
+                );
+            } else {
+                println!(
+                    "{} {} into {} (was {:.7})",
+                    "Merged".green(),
+                    upstream,
+                    branch.name.bright_green(),
+                    branch.old
+                );
+            }
+        }
+        ActionKind::Delete | ActionKind::CheckoutAndDelete => {
+            if opts.dry_run {
+                println!(
+                    "{} {} ({}, at {:.7})",
+                    "Would delete branch".magenta(),
+                    branch.name.bright_magenta(),
+                    branch.action.as_str(),
+                    branch.old
+                );
+            } else {
+                println!(
+                    "{} {} (was {:.7})",
+                    "Deleted branch".magenta(),
+                    branch.name.bright_magenta(),
+                    branch.old
+                );
+                if branch.backup.is_some() {
+                    println!(
+                        "  (use \"git hubsync restore {}\" to restore it)",
+                        branch.name
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn print_human(opts: &SyncOptions, report: &SyncReport) {
+    if report.remotes.is_empty() {
+        return;
+    }
+    println!(
+        "current branch: {}",
+        report.current_branch.as_deref().unwrap_or("(no branch)")
+    );
+    print_remotes(opts, &report.remotes);
+    println!();
+    if report.autostash && opts.dry_run {
+        println!("Would stash local changes");
+    }
+    for branch in &report.branches {
+        print_branch(opts, branch);
+    }
+    for warning in &report.warnings {
+        println!("{}: {}", "warning".bright_yellow(), warning);
+    }
+}
+
+fn oid_json(oid: Oid) -> Value {
+    if oid.is_zero() {
+        Value::Null
+    } else {
+        Value::String(oid.to_string())
+    }
+}
+
+fn ref_update_json(update: &RefUpdate) -> Value {
+    json!({
+        "refname": update.refname,
+        "kind": update.kind.as_str(),
+        "src": update.src,
+        "dst": update.dst,
+        "old_oid": oid_json(update.old),
+        "new_oid": oid_json(update.new),
+        "created": update.is_new(),
+        "deleted": update.is_deleted(),
+        "forced": update.forced,
+    })
+}
+
+fn remote_json(remote: &RemoteReport) -> Value {
+    json!({
+        "name": remote.name,
+        "fetched": remote.fetched,
+        "remote_default": remote.remote_default,
+        "local_default": remote.local_default,
+        "alternate_remote": remote.alternate_remote,
+        "updates": remote.updates.iter().map(ref_update_json).collect::<Vec<_>>(),
+        "messages": remote.messages,
+    })
+}
+
+fn branch_json(branch: &BranchReport) -> Value {
+    json!({
+        "name": branch.name,
+        "action": branch.action.as_str(),
+        "upstream": branch.upstream,
+        "old_oid": oid_json(branch.old),
+        "new_oid": branch.new.map(oid_json).unwrap_or(Value::Null),
+        "checkout": branch.checkout,
+        "worktree": branch.worktree.as_ref().map(|path| path.display().to_string()),
+        "ahead": branch.divergence.map(|d| d.ahead),
+        "behind": branch.divergence.map(|d| d.behind),
+        "merge_base": branch.divergence.and_then(|d| d.base).map(oid_json),
+        "backup": branch.backup,
+        "warning": branch.warning,
+    })
+}
+
+fn to_json(opts: &SyncOptions, report: &SyncReport, error: Option<&dyn Error>) -> Value {
+    json!({
+        "version": 1,
+        "dry_run": opts.dry_run,
+        "current_branch": report.current_branch,
+        "remotes": report.remotes.iter().map(remote_json).collect::<Vec<_>>(),
+        "branches": report.branches.iter().map(branch_json).collect::<Vec<_>>(),
+        "autostash": report.autostash,
+        "warnings": report.warnings,
+        "error": error.map(|e| e.to_string()),
+    })
+}
+
+fn print_porcelain(report: &SyncReport, error: Option<&dyn Error>) {
+    write_porcelain(&mut io::stdout().lock(), report, error).expect("failed printing to stdout");
+}
+
+fn write_porcelain(
+    out: &mut impl Write,
+    report: &SyncReport,
+    error: Option<&dyn Error>,
+) -> io::Result<()> {
+    let or_dash = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+    if !report.remotes.is_empty() {
+        writeln!(out, "current {}", or_dash(&report.current_branch))?;
+    }
+    for remote in &report.remotes {
+        writeln!(
+            out,
+            "remote {} {} {}",
+            remote.name,
+            or_dash(&remote.remote_default),
+            or_dash(&remote.local_default)
+        )?;
+        for update in &remote.updates {
+            let flag = if update.is_new() {
+                "new"
+            } else if update.is_deleted() {
+                "deleted"
+            } else if update.forced {
+                "forced"
+            } else {
+                "fast-forward"
+            };
+            writeln!(
+                out,
+                "update {} {} {} {} {}",
+                remote.name, flag, update.old, update.new, update.refname
+            )?;
+        }
+        for message in &remote.messages {
+            writeln!(out, "message {} {}", remote.name, message)?;
+        }
+    }
+    for branch in &report.branches {
+        if let Some(ref checkout) = branch.checkout {
+            writeln!(out, "checkout {}", checkout)?;
+        }
+        writeln!(
+            out,
+            "branch {} {} {} {} {}",
+            branch.name,
+            branch.action.as_str(),
+            branch.old,
+            branch.new.unwrap_or_else(Oid::zero),
+            or_dash(&branch.upstream)
+        )?;
+        if let Some(ref path) = branch.worktree {
+            writeln!(out, "worktree {} {}", branch.name, path.display())?;
+        }
+        if let Some(divergence) = branch.divergence {
+            writeln!(
+                out,
+                "divergence {} {} {} {}",
+                branch.name,
+                divergence.ahead,
+                divergence.behind,
+                divergence.base.unwrap_or_else(Oid::zero)
+            )?;
+        }
+        if let Some(ref backup) = branch.backup {
+            writeln!(out, "backup {} {}", branch.name, backup)?;
+        }
+        if let Some(ref warning) = branch.warning {
+            writeln!(out, "warning {} {}", branch.name, warning)?;
+        }
+    }
+    if report.autostash {
+        writeln!(out, "autostash")?;
+    }
+    for warning in &report.warnings {
+        writeln!(out, "warning - {}", warning)?;
+    }
+    if let Some(e) = error {
+        writeln!(out, "error {}", e.to_string().replace('\n', " "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use git2::Oid;
+    use serde_json::Value;
+
+    use git_hubsync::{
+        ActionKind, BranchReport, Divergence, RefKind, RefUpdate, RemoteReport, SyncOptions,
+        SyncReport,
+    };
+
+    use super::{to_json, write_porcelain};
+
+    fn oid(c: char) -> Oid {
+        Oid::from_str(&c.to_string().repeat(40)).unwrap()
+    }
+
+    fn update(name: &str, old: Oid, new: Oid, forced: bool) -> RefUpdate {
+        RefUpdate {
+            refname: format!("refs/remotes/origin/{}", name),
+            kind: RefKind::Branch,
+            src: name.to_string(),
+            dst: format!("origin/{}", name),
+            old,
+            new,
+            forced,
+        }
+    }
+
+    fn branch(name: &str, action: ActionKind, upstream: Option<&str>) -> BranchReport {
+        BranchReport {
+            name: name.to_string(),
+            action,
+            upstream: upstream.map(str::to_string),
+            old: oid('1'),
+            new: Some(oid('1')),
+            checkout: None,
+            worktree: None,
+            divergence: None,
+            backup: None,
+            warning: None,
+        }
+    }
+
+    /// Report with new, deleted and forced updates, and up-to-date, deleted and unpushed
+    /// branches.
+    fn sample_report() -> SyncReport {
+        let mut topic = branch("topic", ActionKind::CheckoutAndDelete, None);
+        topic.new = None;
+        topic.checkout = Some("main".to_string());
+        topic.backup = Some("refs/hubsync/trash/2024-01-31/topic".to_string());
+        let mut wip = branch("wip", ActionKind::Unpushed, Some("origin/wip"));
+        wip.divergence = Some(Divergence {
+            ahead: 2,
+            behind: 1,
+            base: Some(oid('3')),
+        });
+        wip.warning = Some("'wip' has diverged".to_string());
+        SyncReport {
+            current_branch: Some("topic".to_string()),
+            remotes: vec![RemoteReport {
+                name: "origin".to_string(),
+                fetched: true,
+                updates: vec![
+                    update("new", Oid::zero(), oid('a'), false),
+                    update("gone", oid('b'), Oid::zero(), false),
+                    update("wip", oid('c'), oid('d'), true),
+                ],
+                messages: vec!["Counting objects: 3".to_string()],
+                remote_default: Some("origin/main".to_string()),
+                local_default: Some("main".to_string()),
+                alternate_remote: None,
+            }],
+            branches: vec![
+                branch("main", ActionKind::UpToDate, Some("origin/main")),
+                topic,
+                wip,
+            ],
+            autostash: false,
+            warnings: vec!["tag 'v1' differs".to_string()],
+        }
+    }
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_json() {
+        let json = to_json(&SyncOptions::default(), &sample_report(), None);
+
+        assert_eq!(
+            keys(&json),
+            [
+                "autostash",
+                "branches",
+                "current_branch",
+                "dry_run",
+                "error",
+                "remotes",
+                "version",
+                "warnings"
+            ]
+        );
+        assert_eq!(json["version"], 1);
+        let remote = &json["remotes"][0];
+        assert_eq!(
+            keys(remote),
+            [
+                "alternate_remote",
+                "fetched",
+                "local_default",
+                "messages",
+                "name",
+                "remote_default",
+                "updates"
+            ]
+        );
+        let updates = remote["updates"].as_array().unwrap();
+        assert_eq!(
+            keys(&updates[0]),
+            [
+                "created", "deleted", "dst", "forced", "kind", "new_oid", "old_oid", "refname",
+                "src"
+            ]
+        );
+        assert_eq!(updates[0]["created"], true);
+        assert_eq!(updates[0]["old_oid"], Value::Null);
+        assert_eq!(updates[1]["deleted"], true);
+        assert_eq!(updates[1]["new_oid"], Value::Null);
+        assert_eq!(updates[2]["forced"], true);
+        assert_eq!(updates[2]["kind"], "branch");
+
+        let branches = json["branches"].as_array().unwrap();
+        assert_eq!(
+            keys(&branches[0]),
+            [
+                "action",
+                "ahead",
+                "backup",
+                "behind",
+                "checkout",
+                "merge_base",
+                "name",
+                "new_oid",
+                "old_oid",
+                "upstream",
+                "warning",
+                "worktree"
+            ]
+        );
+        assert_eq!(branches[0]["action"], "up-to-date");
+        assert_eq!(branches[0]["upstream"], "origin/main");
+        assert_eq!(branches[1]["action"], "checkout-and-delete");
+        assert_eq!(branches[1]["new_oid"], Value::Null);
+        assert_eq!(branches[1]["checkout"], "main");
+        assert_eq!(branches[1]["backup"], "refs/hubsync/trash/2024-01-31/topic");
+        assert_eq!(branches[2]["ahead"], 2);
+        assert_eq!(branches[2]["behind"], 1);
+        assert_eq!(branches[2]["merge_base"], "3".repeat(40));
+        assert_eq!(json["error"], Value::Null);
+    }
+
+    #[test]
+    fn test_porcelain() {
+        let mut out = vec![];
+        let error: Box<dyn Error> = "fetch failed\nretry".into();
+        write_porcelain(&mut out, &sample_report(), Some(error.as_ref())).unwrap();
+
+        let (zero, a, b, c, d) = (Oid::zero(), oid('a'), oid('b'), oid('c'), oid('d'));
+        let (one, three) = (oid('1'), oid('3'));
+        let expected = format!(
+            "current topic
+remote origin origin/main main
+update origin new {zero} {a} refs/remotes/origin/new
+update origin deleted {b} {zero} refs/remotes/origin/gone
+update origin forced {c} {d} refs/remotes/origin/wip
+message origin Counting objects: 3
+branch main up-to-date {one} {one} origin/main
+checkout main
+branch topic checkout-and-delete {one} {zero} -
+backup topic refs/hubsync/trash/2024-01-31/topic
+branch wip unpushed {one} {one} origin/wip
+divergence wip 2 1 {three}
+warning wip 'wip' has diverged
+warning - tag 'v1' differs
+error fetch failed retry
+"
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
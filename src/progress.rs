@@ -0,0 +1,310 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use git2::Progress;
+
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress line of a fetch, rewritten in place on stderr, and messages of the remote.
+pub struct ProgressLine<W: Write = io::Stderr> {
+    out: W,
+    /// Draw the progress line, otherwise only complete remote messages are printed.
+    enabled: bool,
+    start: Instant,
+    last: Option<Instant>,
+    width: usize,
+    /// Sideband data after the last line break.
+    pending: Vec<u8>,
+    /// Last segment of the pending line terminated by '\r'.
+    overwritten: Vec<u8>,
+    messages: Vec<String>,
+}
+
+/// Counts of a fetch, as in `git2::Progress`.
+#[derive(Clone, Copy, Debug, Default)]
+struct Stats {
+    received_objects: usize,
+    total_objects: usize,
+    indexed_objects: usize,
+    indexed_deltas: usize,
+    total_deltas: usize,
+    received_bytes: usize,
+}
+
+impl From<&Progress<'_>> for Stats {
+    fn from(progress: &Progress) -> Self {
+        Stats {
+            received_objects: progress.received_objects(),
+            total_objects: progress.total_objects(),
+            indexed_objects: progress.indexed_objects(),
+            indexed_deltas: progress.indexed_deltas(),
+            total_deltas: progress.total_deltas(),
+            received_bytes: progress.received_bytes(),
+        }
+    }
+}
+
+impl ProgressLine {
+    pub fn new(enabled: bool) -> Self {
+        ProgressLine::with_writer(enabled, io::stderr())
+    }
+}
+
+impl<W: Write> ProgressLine<W> {
+    fn with_writer(enabled: bool, out: W) -> Self {
+        ProgressLine {
+            out,
+            enabled,
+            start: Instant::now(),
+            last: None,
+            width: 0,
+            pending: vec![],
+            overwritten: vec![],
+            messages: vec![],
+        }
+    }
+
+    /// Relay sideband data of the remote with "remote: " prefix, like git.
+    /// Lines ending with '\r' are progress of the remote, drawn in place if enabled.
+    pub fn sideband(&mut self, data: &[u8]) {
+        for &b in data {
+            match b {
+                b'\r' => {
+                    self.overwritten = std::mem::take(&mut self.pending);
+                    if self.enabled && !self.overwritten.is_empty() {
+                        let line =
+                            format!("remote: {}", String::from_utf8_lossy(&self.overwritten));
+                        self.draw(&line);
+                    }
+                }
+                b'\n' => {
+                    let line = if self.pending.is_empty() {
+                        std::mem::take(&mut self.overwritten)
+                    } else {
+                        self.overwritten.clear();
+                        std::mem::take(&mut self.pending)
+                    };
+                    self.message(String::from_utf8_lossy(&line).into_owned());
+                }
+                _ => self.pending.push(b),
+            }
+        }
+    }
+
+    /// Messages of the remote printed so far, without "remote: " prefix.
+    pub fn into_messages(self) -> Vec<String> {
+        self.messages
+    }
+
+    fn message(&mut self, message: String) {
+        self.clear();
+        // Errors are ignored, as messages are also kept in the report.
+        let _ = writeln!(self.out, "remote: {}", message);
+        self.messages.push(message);
+    }
+
+    /// Redraw the line with the stats, at most every 100ms unless a phase is complete.
+    pub fn update(&mut self, progress: &Progress) {
+        self.update_stats(Stats::from(progress));
+    }
+
+    fn update_stats(&mut self, stats: Stats) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let done = stats.received_objects == stats.total_objects
+            && stats.indexed_deltas == stats.total_deltas;
+        if !done && self.last.is_some_and(|last| now - last < INTERVAL) {
+            return;
+        }
+        self.last = Some(now);
+        let line = if stats.received_objects < stats.total_objects {
+            let elapsed = (now - self.start).as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                stats.received_bytes as f64 / elapsed
+            } else {
+                0.0
+            };
+            format!(
+                "Receiving objects: {:3}% ({}/{}), indexed {}, {} | {}/s",
+                percent(stats.received_objects, stats.total_objects),
+                stats.received_objects,
+                stats.total_objects,
+                stats.indexed_objects,
+                human_bytes(stats.received_bytes as f64),
+                human_bytes(rate)
+            )
+        } else if stats.total_deltas > 0 {
+            format!(
+                "Resolving deltas: {:3}% ({}/{}), {}",
+                percent(stats.indexed_deltas, stats.total_deltas),
+                stats.indexed_deltas,
+                stats.total_deltas,
+                human_bytes(stats.received_bytes as f64)
+            )
+        } else {
+            format!(
+                "Indexing objects: {:3}% ({}/{}), {}",
+                percent(stats.indexed_objects, stats.total_objects),
+                stats.indexed_objects,
+                stats.total_objects,
+                human_bytes(stats.received_bytes as f64)
+            )
+        };
+        self.draw(&line);
+    }
+
+    /// Erase the line, if anything was drawn, and print the incomplete remote message.
+    pub fn finish(&mut self) {
+        self.clear();
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.message(String::from_utf8_lossy(&line).into_owned());
+        }
+    }
+
+    fn clear(&mut self) {
+        if self.width > 0 {
+            self.draw("");
+        }
+    }
+
+    fn draw(&mut self, line: &str) {
+        let width = line.chars().count();
+        let pad = self.width.saturating_sub(width);
+        // Errors are ignored, progress is only informative.
+        let _ = write!(self.out, "\r{}{:pad$}\r", line, "", pad = pad);
+        let _ = self.out.flush();
+        self.width = width;
+    }
+}
+
+fn percent(n: usize, total: usize) -> usize {
+    (n * 100).checked_div(total).unwrap_or(100)
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024.0 {
+        return format!("{} bytes", bytes as u64);
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.2} {}", value, unit)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{ProgressLine, Stats, human_bytes};
+
+    fn output(line: &mut ProgressLine<Vec<u8>>) -> String {
+        String::from_utf8(std::mem::take(&mut line.out)).unwrap()
+    }
+
+    #[test]
+    fn test_sideband() {
+        let mut line = ProgressLine::with_writer(true, vec![]);
+
+        line.sideband(b"Counting objects: 1\rCounting objects: 2\r");
+        assert_eq!(
+            output(&mut line),
+            "\rremote: Counting objects: 1\r\rremote: Counting objects: 2\r"
+        );
+
+        // Split across packets, and the final state of a '\r' line ended by '\n'.
+        line.sideband(b"Counting objects: 3, ");
+        line.sideband(b"done.\nCompressing: 100%\r\nTotal 3\n");
+        assert_eq!(
+            output(&mut line),
+            format!(
+                "\r{:27}\rremote: Counting objects: 3, done.\n\
+                 \rremote: Compressing: 100%\r\r{:25}\rremote: Compressing: 100%\n\
+                 remote: Total 3\n",
+                "", ""
+            )
+        );
+
+        line.sideband(b"incomplete");
+        line.finish();
+        assert_eq!(output(&mut line), "remote: incomplete\n");
+        assert_eq!(
+            line.into_messages(),
+            [
+                "Counting objects: 3, done.",
+                "Compressing: 100%",
+                "Total 3",
+                "incomplete"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sideband_disabled() {
+        let mut line = ProgressLine::with_writer(false, vec![]);
+
+        line.sideband(b"Counting objects: 1\rCounting objects: 2, done.\n");
+        line.update_stats(Stats {
+            received_objects: 1,
+            total_objects: 4,
+            ..Stats::default()
+        });
+        line.finish();
+
+        assert_eq!(output(&mut line), "remote: Counting objects: 2, done.\n");
+    }
+
+    #[test]
+    fn test_update() {
+        let mut line = ProgressLine::with_writer(true, vec![]);
+        line.start = Instant::now() - Duration::from_secs(2);
+        let mut stats = Stats {
+            received_objects: 1,
+            total_objects: 4,
+            indexed_objects: 1,
+            total_deltas: 2,
+            received_bytes: 3 * 1024 * 1024,
+            ..Stats::default()
+        };
+
+        line.update_stats(stats);
+        let receiving = "Receiving objects:  25% (1/4), indexed 1, 3.00 MiB | 1.50 MiB/s";
+        assert_eq!(output(&mut line), format!("\r{}\r", receiving));
+
+        // At most every 100ms.
+        stats.received_objects = 2;
+        line.update_stats(stats);
+        assert_eq!(output(&mut line), "");
+
+        // Unless complete.
+        stats.received_objects = 4;
+        stats.indexed_objects = 4;
+        stats.indexed_deltas = 2;
+        line.update_stats(stats);
+        let resolving = "Resolving deltas: 100% (2/2), 3.00 MiB";
+        let pad = receiving.len() - resolving.len();
+        assert_eq!(
+            output(&mut line),
+            format!("\r{}{:pad$}\r", resolving, "", pad = pad)
+        );
+
+        line.finish();
+        assert_eq!(output(&mut line), format!("\r{:1$}\r", "", resolving.len()));
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(1023.0), "1023 bytes");
+        assert_eq!(human_bytes(1536.0), "1.50 KiB");
+        assert_eq!(human_bytes(5.0 * 1024.0 * 1024.0 * 1024.0), "5.00 GiB");
+    }
+}
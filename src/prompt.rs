@@ -0,0 +1,84 @@
+use std::cell::Cell;
+use std::env;
+use std::error::Error;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use git2::Config;
+use git2_credentials::CredentialUI;
+use git2_credentials::ui4dialoguer::CredentialUI4Dialoguer;
+
+use crate::err::GitError;
+
+/// Asks for credentials that helpers, the SSH agent and key files did not provide, like git:
+/// with `GIT_ASKPASS`, `core.askPass` or `SSH_ASKPASS` if set, otherwise on the terminal
+/// unless prompting is disabled.
+pub struct Prompt {
+    askpass: Option<String>,
+    terminal: bool,
+    /// Set if credentials were needed but could not be asked for.
+    needed: Rc<Cell<bool>>,
+}
+
+impl Prompt {
+    pub fn new(config: &Config, terminal: bool, needed: Rc<Cell<bool>>) -> Self {
+        let askpass = env::var("GIT_ASKPASS")
+            .ok()
+            .or_else(|| config.get_string("core.askPass").ok())
+            .or_else(|| env::var("SSH_ASKPASS").ok())
+            .filter(|askpass| !askpass.is_empty());
+        Prompt {
+            askpass,
+            terminal,
+            needed,
+        }
+    }
+
+    /// Fail unless the terminal may be used, remembering that credentials were needed.
+    fn check_terminal(&self) -> Result<(), Box<dyn Error>> {
+        if !self.terminal {
+            self.needed.set(true);
+            return Err(GitError::new("terminal prompts disabled".to_string()).into());
+        }
+        Ok(())
+    }
+}
+
+impl CredentialUI for Prompt {
+    fn ask_user_password(&self, username: &str) -> Result<(String, String), Box<dyn Error>> {
+        let Some(askpass) = &self.askpass else {
+            self.check_terminal()?;
+            return CredentialUI4Dialoguer.ask_user_password(username);
+        };
+        let username = if username.is_empty() {
+            askpass_run(askpass, "Username: ")?
+        } else {
+            username.to_string()
+        };
+        let password = askpass_run(askpass, &format!("Password for '{}': ", username))?;
+        Ok((username, password))
+    }
+
+    fn ask_ssh_passphrase(&self, passphrase_prompt: &str) -> Result<String, Box<dyn Error>> {
+        let Some(askpass) = &self.askpass else {
+            self.check_terminal()?;
+            return CredentialUI4Dialoguer.ask_ssh_passphrase(passphrase_prompt);
+        };
+        askpass_run(askpass, &format!("{}: ", passphrase_prompt))
+    }
+}
+
+/// Run the askpass program with the prompt, and return the first line of its output.
+fn askpass_run(askpass: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new(askpass)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| GitError::new(format!("unable to run askpass '{}': {}", askpass, e)))?;
+    if !output.status.success() {
+        return Err(GitError::new(format!("askpass '{}' failed", askpass)).into());
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout.lines().next().unwrap_or("").to_string())
+}
@@ -0,0 +1,139 @@
+use std::env;
+use std::error::Error;
+
+use git2::Config;
+use url::Url;
+
+use crate::http;
+
+/// Access token of a forge, sent as the password of HTTP basic authentication.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Token {
+    pub username: String,
+    pub token: String,
+}
+
+/// Environment variables of tokens and the user name for them, by host.
+const FORGES: &[(&str, &[&str], &str)] = &[
+    (
+        "github.com",
+        &["GH_TOKEN", "GITHUB_TOKEN"],
+        "x-access-token",
+    ),
+    ("gitlab.com", &["GITLAB_TOKEN"], "oauth2"),
+];
+
+/// Token for the URL, from the environment variable of `hubsync.<url>.tokenEnv`, or
+/// `GH_TOKEN`/`GITHUB_TOKEN` for github.com and `GITLAB_TOKEN` for gitlab.com, which
+/// `hubsync.tokenEnv` without URL overrides.
+/// The user name is `hubsync.<url>.tokenUser`, the one in the URL or the one of the forge.
+///
+/// Tokens are only sent over HTTPS, or over HTTP if configured for a `http://` URL.
+pub fn token_for(config: &Config, url: &str) -> Result<Option<Token>, Box<dyn Error>> {
+    token_for_with(config, url, env_var)
+}
+
+/// `token_for` with the environment variables looked up by `env`.
+pub fn token_for_with(
+    config: &Config,
+    url: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Token>, Box<dyn Error>> {
+    let Ok(target) = Url::parse(url) else {
+        return Ok(None);
+    };
+    if target.scheme() != "http" && target.scheme() != "https" {
+        return Ok(None);
+    }
+    let forge = target.host_str().and_then(|host| {
+        FORGES
+            .iter()
+            .find(|(name, _, _)| host.eq_ignore_ascii_case(name))
+    });
+    let token = match http::lookup_url(config, "hubsync", "tokenEnv", url)? {
+        Some((Some(_), name)) => env(&name),
+        _ if target.scheme() != "https" => return Ok(None),
+        // Without URL, the key only renames the variable of the forges, so that a token
+        // is never sent to other hosts.
+        Some((None, name)) => forge.and_then(|_| env(&name)),
+        None => forge.and_then(|(_, names, _)| names.iter().find_map(|name| env(name))),
+    };
+    let Some(token) = token else {
+        return Ok(None);
+    };
+    let username = match http::lookup_url(config, "hubsync", "tokenUser", url)? {
+        Some((_, username)) => username,
+        None if !target.username().is_empty() => target.username().to_string(),
+        None => forge
+            .map_or("oauth2", |(_, _, username)| username)
+            .to_string(),
+    };
+    Ok(Some(Token { username, token }))
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{Token, token_for_with};
+    use crate::http::test::config_with;
+
+    fn token(username: &str) -> Option<Token> {
+        Some(Token {
+            username: username.to_string(),
+            token: "t0ken".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_token_for() {
+        let config = config_with(&[
+            ("hubsync.tokenEnv", "HUBSYNC_TEST_TOKEN"),
+            (
+                "hubsync.https://git.example.org.tokenEnv",
+                "HUBSYNC_TEST_TOKEN",
+            ),
+            ("hubsync.https://git.example.org/team.tokenUser", "bot"),
+            (
+                "hubsync.http://127.0.0.1:8080/.tokenEnv",
+                "HUBSYNC_TEST_TOKEN",
+            ),
+            (
+                "hubsync.https://git.example.com.tokenEnv",
+                "HUBSYNC_NO_TOKEN",
+            ),
+        ]);
+        let vars = HashMap::from([("HUBSYNC_TEST_TOKEN", "t0ken"), ("HUBSYNC_NO_TOKEN", "")]);
+        let env = |name: &str| {
+            vars.get(name)
+                .filter(|value| !value.is_empty())
+                .map(|value| value.to_string())
+        };
+        let token_for = |url| token_for_with(&config, url, env).unwrap();
+
+        assert_eq!(
+            token_for("https://github.com/iquiw/git-hubsync"),
+            token("x-access-token")
+        );
+        assert_eq!(token_for("https://gitlab.com/group/repo"), token("oauth2"));
+        assert_eq!(
+            token_for("https://git.example.org/team/repo.git"),
+            token("bot")
+        );
+        assert_eq!(
+            token_for("https://user@git.example.org/repo.git"),
+            token("user")
+        );
+        assert_eq!(token_for("https://git.example.com/repo.git"), None);
+        // The key without URL is only for the forges.
+        assert_eq!(token_for("https://git.example.net/repo.git"), None);
+        // Not sent over HTTP unless configured for the URL.
+        assert_eq!(token_for("http://git.example.org/repo.git"), None);
+        assert_eq!(token_for("http://127.0.0.1:8080/repo.git"), token("oauth2"));
+        assert_eq!(token_for("git@github.com:iquiw/git-hubsync.git"), None);
+    }
+}